@@ -0,0 +1,62 @@
+//! Compares writing the counters of a flush with `Metric::to_line` against the serializer, which
+//! caches the escaped measurement and tags of every key.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use metrics::{Key, Label};
+use metrics_influxdb::bench::Counters;
+use metrics_influxdb::metric::{LineFormat, Metric, Timestamp};
+
+const KEYS: usize = 10_000;
+const ITERATIONS: u32 = 100;
+
+fn main() {
+    let keys = (0..KEYS)
+        .map(|index| {
+            Key::from_parts(
+                format!("http.requests.{}", index % 100),
+                vec![
+                    Label::new("user", (index / 100).to_string()),
+                    Label::new("method", "GET"),
+                ],
+            )
+        })
+        .collect::<Vec<Key>>();
+
+    let format = LineFormat::default();
+    let to_line = time(|| {
+        let timestamp = Timestamp::now();
+        keys.iter()
+            .enumerate()
+            .map(|(value, key)| {
+                key.labels()
+                    .fold(Metric::new(key.name()), |metric, label| {
+                        metric.tag(label.key(), label.value())
+                    })
+                    .field("value", value as u64)
+                    .timestamp(timestamp)
+                    .to_line(&format)
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+            .len()
+    });
+
+    let counters = Counters::new(&keys);
+    let serializer = time(|| counters.write(Timestamp::now()));
+
+    println!("{KEYS} counters, mean of {ITERATIONS} flushes");
+    println!("Metric::to_line and join: {to_line:?}");
+    println!("LineSerializer::writer:   {serializer:?}");
+}
+
+/// Returns the mean duration of `write`, after a first call that fills the caches.
+fn time(mut write: impl FnMut() -> usize) -> Duration {
+    black_box(write());
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(write());
+    }
+    start.elapsed() / ITERATIONS
+}
@@ -0,0 +1,62 @@
+/// Splits `lines` into consecutive batches holding at most `max_lines` lines and `max_bytes`
+/// bytes once joined with newlines, `len` gives the length of a line in bytes.
+///
+/// A line that is larger than `max_bytes` on its own is put in a batch by itself, the server
+/// gets to decide whether it accepts it.
+pub(crate) fn batches<T>(
+    lines: &[T],
+    max_lines: usize,
+    max_bytes: usize,
+    len: impl Fn(&T) -> usize,
+) -> Vec<&[T]> {
+    let max_lines = max_lines.max(1);
+    let mut batches = vec![];
+    let mut start = 0;
+    let mut bytes = 0;
+
+    for (index, line) in lines.iter().enumerate() {
+        let len = len(line);
+        let count = index - start;
+        // Every line after the first one is preceded by a newline.
+        let added = if count == 0 { len } else { len + 1 };
+
+        if count > 0 && (count == max_lines || bytes + added > max_bytes) {
+            batches.push(&lines[start..index]);
+            start = index;
+            bytes = len;
+        } else {
+            bytes += added;
+        }
+    }
+
+    if start < lines.len() {
+        batches.push(&lines[start..]);
+    }
+    batches
+}
+
+#[cfg(test)]
+mod test {
+    use crate::batch::*;
+
+    #[test]
+    fn test_batches_by_lines() {
+        let lines = ["a", "b", "c", "d", "e"];
+        let expected: Vec<&[&str]> = vec![&["a", "b"], &["c", "d"], &["e"]];
+        assert_eq!(expected, batches(&lines, 2, usize::MAX, |line| line.len()));
+    }
+
+    #[test]
+    fn test_batches_by_bytes() {
+        let lines = ["aaa", "bbb", "c", "dddddd", "e"];
+        // "aaa\nbbb" is 7 bytes, adding "\nc" would make it 9.
+        let expected: Vec<&[&str]> = vec![&["aaa", "bbb"], &["c"], &["dddddd"], &["e"]];
+        assert_eq!(expected, batches(&lines, usize::MAX, 7, |line| line.len()));
+    }
+
+    #[test]
+    fn test_batches_empty() {
+        let lines: [&str; 0] = [];
+        assert!(batches(&lines, 10, 10, |line| line.len()).is_empty());
+    }
+}
@@ -0,0 +1,33 @@
+//! Entry points for the benchmarks in `benches/`, which only see the public API.
+
+use metrics::Key;
+
+use crate::config::RecorderConfig;
+use crate::metric::{LineFormat, Timestamp};
+use crate::serializer::LineSerializer;
+
+/// Counters written by the serializer of a flush.
+pub struct Counters {
+    serializer: LineSerializer,
+    keys: Vec<Key>,
+}
+
+impl Counters {
+    pub fn new(keys: &[Key]) -> Self {
+        Counters {
+            serializer: LineSerializer::new(LineFormat::default(), &RecorderConfig::default()),
+            keys: keys.to_vec(),
+        }
+    }
+
+    /// Writes a line for every key, with its index as the value, and returns the size of the
+    /// request body they make.
+    pub fn write(&self, timestamp: Timestamp) -> usize {
+        let mut writer = self.serializer.writer(timestamp);
+        for (value, key) in self.keys.iter().enumerate() {
+            writer.counter(key, value as u64);
+        }
+        let lines = writer.finish();
+        lines.join(&lines.ranges).len()
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dashmap::{DashMap, DashSet};
+use metrics::{Key, Label};
+use metrics_util::MetricKind;
+
+use crate::config::{RecorderConfig, SeriesOverflow};
+
+/// Value of every label of the series that label sets over the limits are folded into.
+pub(crate) const OVERFLOW: &str = "__overflow__";
+
+/// Number of rejected series remembered per metric, the ones beyond are never counted as rejected.
+const MAX_REJECTED_PER_METRIC: usize = 1024;
+
+/// What happens to a series being registered.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Admission {
+    Admitted,
+    /// Over the limits, it is folded into the given overflow series. `first` tells whether the
+    /// series is rejected for the first time.
+    Folded {
+        key: Key,
+        first: bool,
+    },
+    /// Over the limits, it is dropped.
+    Dropped {
+        first: bool,
+    },
+}
+
+/// Caps the number of distinct label sets, per metric and over all metrics.
+///
+/// Admitted series are tracked apart from the registry, which has no way to tell whether a key is
+/// already in it without creating it. Nothing is tracked when there are no limits.
+pub(crate) struct SeriesLimiter {
+    max_series: Option<usize>,
+    max_series_per_metric: Option<usize>,
+    overflow: SeriesOverflow,
+    /// Admitted series, by metric kind.
+    series: [DashSet<Key>; 3],
+    /// Number of admitted series, by metric kind and name.
+    per_metric: [DashMap<String, usize>; 3],
+    total: AtomicUsize,
+    /// Hashes of the rejected series, by metric kind and name.
+    rejected: [DashMap<String, HashSet<u64>>; 3],
+}
+
+impl SeriesLimiter {
+    pub(crate) fn new(config: &RecorderConfig) -> Self {
+        SeriesLimiter {
+            max_series: config.max_series,
+            max_series_per_metric: config.max_series_per_metric,
+            overflow: config.series_overflow.clone(),
+            series: Default::default(),
+            per_metric: Default::default(),
+            total: AtomicUsize::new(0),
+            rejected: Default::default(),
+        }
+    }
+
+    /// Decides whether the series `key` of the given kind is registered.
+    pub(crate) fn admit(&self, kind: MetricKind, key: &Key) -> Admission {
+        if self.max_series.is_none() && self.max_series_per_metric.is_none() {
+            return Admission::Admitted;
+        }
+        let series = &self.series[index(kind)];
+        let per_metric = &self.per_metric[index(kind)];
+
+        // Holding the count of the metric serializes the registrations and releases of its series.
+        let mut count = match per_metric.get_mut(key.name()) {
+            Some(count) => count,
+            None => per_metric.entry(key.name().to_owned()).or_insert(0),
+        };
+        if series.contains(key) {
+            return Admission::Admitted;
+        }
+        let admitted = self
+            .max_series_per_metric
+            .is_none_or(|max_series| *count < max_series)
+            && self
+                .total
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |total| {
+                    self.max_series
+                        .is_none_or(|max_series| total < max_series)
+                        .then_some(total + 1)
+                })
+                .is_ok();
+        if admitted {
+            *count += 1;
+            series.insert(key.clone());
+            return Admission::Admitted;
+        }
+        if *count == 0 {
+            drop(count);
+            per_metric.remove_if(key.name(), |_, count| *count == 0);
+        }
+
+        let first = self.reject(kind, key);
+        match self.overflow {
+            SeriesOverflow::Drop => Admission::Dropped { first },
+            SeriesOverflow::Fold => Admission::Folded {
+                key: overflow_key(key),
+                first,
+            },
+        }
+    }
+
+    /// Remembers that the series `key` of the given kind was rejected, returning whether it was
+    /// not before.
+    fn reject(&self, kind: MetricKind, key: &Key) -> bool {
+        let rejected = &self.rejected[index(kind)];
+        let mut hashes = match rejected.get_mut(key.name()) {
+            Some(hashes) => hashes,
+            None => rejected.entry(key.name().to_owned()).or_default(),
+        };
+        hashes.len() < MAX_REJECTED_PER_METRIC && hashes.insert(key.get_hash())
+    }
+
+    /// Forgets the series `key` of the given kind, which was removed from the registry.
+    ///
+    /// Should the series be registered again between its removal and this call, it is tracked
+    /// again on its next registration.
+    pub(crate) fn release(&self, kind: MetricKind, key: &Key) {
+        let per_metric = &self.per_metric[index(kind)];
+        let Some(mut count) = per_metric.get_mut(key.name()) else {
+            return;
+        };
+        if self.series[index(kind)].remove(key).is_none() {
+            return;
+        }
+        *count -= 1;
+        self.total.fetch_sub(1, Ordering::AcqRel);
+        drop(count);
+        per_metric.remove_if(key.name(), |_, count| *count == 0);
+    }
+}
+
+fn index(kind: MetricKind) -> usize {
+    match kind {
+        MetricKind::Counter => 0,
+        MetricKind::Gauge => 1,
+        MetricKind::Histogram => 2,
+    }
+}
+
+/// Returns the series `key` is folded into, which has the same labels with the [`OVERFLOW`]
+/// value.
+fn overflow_key(key: &Key) -> Key {
+    let labels = key
+        .labels()
+        .map(|label| Label::new(label.key().to_owned(), OVERFLOW))
+        .collect::<Vec<Label>>();
+    Key::from_parts(key.name().to_owned(), labels)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::RecorderConfigBuilder;
+
+    use crate::cardinality::*;
+
+    fn key(name: &str, user: usize) -> Key {
+        Key::from_parts(
+            name.to_owned(),
+            vec![
+                Label::new("user", user.to_string()),
+                Label::new("method", "GET"),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_max_series_per_metric() {
+        let config = RecorderConfigBuilder::default()
+            .max_series_per_metric(Some(2))
+            .build()
+            .unwrap();
+        let limiter = SeriesLimiter::new(&config);
+
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit(MetricKind::Counter, &key("requests", 1))
+        );
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit(MetricKind::Counter, &key("requests", 2))
+        );
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit(MetricKind::Counter, &key("requests", 1))
+        );
+        assert_eq!(
+            Admission::Dropped { first: true },
+            limiter.admit(MetricKind::Counter, &key("requests", 3))
+        );
+        assert_eq!(
+            Admission::Dropped { first: false },
+            limiter.admit(MetricKind::Counter, &key("requests", 3))
+        );
+        // Other metrics and kinds have their own limits.
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit(MetricKind::Counter, &key("errors", 3))
+        );
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit(MetricKind::Gauge, &key("requests", 3))
+        );
+
+        limiter.release(MetricKind::Counter, &key("requests", 1));
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit(MetricKind::Counter, &key("requests", 3))
+        );
+        assert_eq!(
+            Admission::Dropped { first: true },
+            limiter.admit(MetricKind::Counter, &key("requests", 1))
+        );
+    }
+
+    #[test]
+    fn test_max_series() {
+        let config = RecorderConfigBuilder::default()
+            .max_series(Some(2))
+            .series_overflow(SeriesOverflow::Fold)
+            .build()
+            .unwrap();
+        let limiter = SeriesLimiter::new(&config);
+
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit(MetricKind::Counter, &key("requests", 1))
+        );
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit(MetricKind::Gauge, &key("sessions", 1))
+        );
+        assert_eq!(
+            Admission::Folded {
+                key: Key::from_parts(
+                    "errors",
+                    vec![Label::new("user", OVERFLOW), Label::new("method", OVERFLOW)],
+                ),
+                first: true,
+            },
+            limiter.admit(MetricKind::Counter, &key("errors", 1))
+        );
+        // Metrics without any series are not tracked.
+        assert!(!limiter.per_metric[0].contains_key("errors"));
+
+        limiter.release(MetricKind::Gauge, &key("sessions", 1));
+        assert!(!limiter.per_metric[1].contains_key("sessions"));
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit(MetricKind::Counter, &key("errors", 1))
+        );
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = SeriesLimiter::new(&RecorderConfig::default());
+        for user in 0..100 {
+            assert_eq!(
+                Admission::Admitted,
+                limiter.admit(MetricKind::Counter, &key("requests", user))
+            );
+        }
+        assert!(limiter.series[0].is_empty());
+    }
+
+    #[test]
+    fn test_max_rejected_per_metric() {
+        let config = RecorderConfigBuilder::default()
+            .max_series_per_metric(Some(1))
+            .build()
+            .unwrap();
+        let limiter = SeriesLimiter::new(&config);
+
+        limiter.admit(MetricKind::Counter, &key("requests", 0));
+        for user in 1..=MAX_REJECTED_PER_METRIC {
+            assert_eq!(
+                Admission::Dropped { first: true },
+                limiter.admit(MetricKind::Counter, &key("requests", user))
+            );
+        }
+        assert_eq!(
+            Admission::Dropped { first: false },
+            limiter.admit(MetricKind::Counter, &key("requests", 0xffff))
+        );
+    }
+}
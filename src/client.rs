@@ -1,19 +1,32 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::borrow::Cow;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use itertools::Itertools;
+use bytes::Bytes;
+use dashmap::DashMap;
 use metrics::{Counter, Gauge, Histogram, Key, KeyName, Recorder, SharedString, Unit};
-use metrics_util::parse_quantiles;
-use metrics_util::registry::Registry;
-use reqwest::{Client, RequestBuilder};
+use metrics_util::registry::{GenerationalStorage, Recency, Registry};
+use metrics_util::{parse_quantiles, MetricKind};
+use quanta::Clock;
+use reqwest::header::{CONTENT_ENCODING, RETRY_AFTER};
+use reqwest::{Client, RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
-use crate::config::InfluxConfig;
-use crate::distribution::DistributionBuilder;
-use crate::error::{InfluxError, Result};
-use crate::metric::Metric;
-use crate::registry::AtomicStorage;
+use crate::batch::batches;
+use crate::cardinality::{Admission, SeriesLimiter};
+use crate::config::{Compression, HistogramSemantics, InfluxConfig, Precision, RecorderConfig};
+use crate::distribution::{Distribution, DistributionBuilder};
+use crate::error::{ApiError, InfluxError, Result};
+use crate::metric::{LineFormat, Metadata, Metric, Timestamp};
+use crate::registry::{AtomicStorage, GenerationalAtomicStorage};
+use crate::report::ErrorReporter;
+use crate::serializer::{LineSerializer, Lines};
+use crate::stats::SelfMetrics;
 
 #[derive(Clone)]
 pub struct InfluxClient {
@@ -21,59 +34,120 @@ pub struct InfluxClient {
 }
 
 impl InfluxClient {
-    pub fn new(config: impl InfluxConfig) -> Self {
-        let client = Client::new();
+    pub fn new(config: impl InfluxConfig) -> reqwest::Result<Self> {
+        Self::with_recorder_config(config, RecorderConfig::default())
+    }
+
+    /// Fails when the HTTP client cannot be built, e.g. when no TLS backend can be initialized.
+    pub fn with_recorder_config(
+        config: impl InfluxConfig,
+        recorder_config: RecorderConfig,
+    ) -> reqwest::Result<Self> {
+        let client = Client::builder().cookie_store(true).build()?;
         let request = config.request(&client);
-        InfluxClient {
-            recorder: InfluxRecorder::new(client, request),
-        }
+        let signin = config.signin(&client);
+        let precision = config.precision();
+        let compression = config.compression();
+        Ok(InfluxClient {
+            recorder: InfluxRecorder::new(
+                client,
+                request,
+                signin,
+                precision,
+                compression,
+                recorder_config,
+            ),
+        })
     }
 
     pub fn recorder(&self) -> InfluxRecorder {
         self.recorder.clone()
     }
 
-    pub fn start(&self, delay: Duration) {
+    /// Spawns a task that flushes the registry every `delay` until the returned handle is shut
+    /// down or dropped.
+    ///
+    /// Keep the handle around, or call [`ReporterHandle::detach`] to report until the runtime
+    /// shuts down.
+    #[must_use = "dropping the handle stops the reporter, use `detach` to keep it running"]
+    pub fn start(&self, delay: Duration) -> ReporterHandle {
         let recorder = self.recorder.clone();
-        tokio::spawn(async move {
+        let (shutdown, mut stop) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut detached = false;
             loop {
-                sleep(delay).await;
-
-                let counter_gauges = recorder
-                    .inner
-                    .registry
-                    .get_counter_handles()
-                    .iter()
-                    .chain(recorder.inner.registry.get_gauge_handles().iter())
-                    .map(|metric| metric.into())
-                    .collect::<Vec<Metric>>();
-
-                let histograms = recorder
-                    .inner
-                    .registry
-                    .get_histogram_handles()
-                    .iter()
-                    .map(|(key, value)| {
-                        let mut distribution =
-                            recorder.inner.distribution_builder.get_distribution();
-                        value.clear_with(|samples| distribution.record_samples(samples));
-                        (key, distribution)
-                    })
-                    .map(|metric| metric.into())
-                    .collect::<Vec<Metric>>();
-
-                let metrics = counter_gauges.into_iter().chain(histograms).join("\n");
-
-                match recorder.write_metrics(metrics).await {
-                    Ok(_) => {}
-                    Err(_) => {}
+                tokio::select! {
+                    _ = sleep(delay) => {
+                        let _ = recorder.flush().await;
+                    }
+                    stopped = &mut stop, if !detached => match stopped {
+                        Ok(()) => break,
+                        // A detached handle drops the sender without sending.
+                        Err(_) => detached = true,
+                    },
                 }
             }
+            recorder.flush().await
         });
+        ReporterHandle {
+            shutdown: Some(shutdown),
+            task: Some(task),
+        }
     }
 
+    /// Writes a single metric. Nothing is sent when its line is empty, see [`Metric::to_line`].
     pub async fn write(&self, metric: &Metric) -> Result<()> {
-        self.recorder.write_metrics(metric.to_string()).await
+        let line = metric.to_line(self.recorder.inner.serializer.format());
+        if line.is_empty() {
+            return Ok(());
+        }
+        self.recorder.write_metrics(line.into()).await
+    }
+}
+
+/// Handle to the background reporter spawned by [`InfluxClient::start`].
+///
+/// Dropping the handle stops the reporter, which then flushes the registry one last time on a
+/// best effort basis, as long as the runtime is still alive to run it. Use
+/// [`ReporterHandle::shutdown`] to wait for that final flush instead, or
+/// [`ReporterHandle::detach`] to keep reporting without holding on to the handle.
+#[must_use = "dropping the handle stops the reporter, use `detach` to keep it running"]
+pub struct ReporterHandle {
+    shutdown: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl ReporterHandle {
+    /// Stops the reporter and waits until in-flight writes and a final flush of the registry are
+    /// done, returning the result of that final flush.
+    pub async fn shutdown(mut self) -> Result<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        let Some(task) = self.task.take() else {
+            return Ok(());
+        };
+        match task.await {
+            Ok(result) => result,
+            // A panic of the reporter is the caller's to handle.
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            // The runtime is going away, there is nothing left to flush.
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Lets the reporter run until the runtime shuts down, without a final flush.
+    pub fn detach(mut self) {
+        self.shutdown.take();
+        self.task.take();
+    }
+}
+
+impl Drop for ReporterHandle {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
     }
 }
 
@@ -85,92 +159,1222 @@ pub struct InfluxRecorder {
 pub struct Inner {
     client: Client,
     request: RequestBuilder,
-    registry: Registry<Key, AtomicStorage>,
+    signin: Option<RequestBuilder>,
+    signed_in: AtomicBool,
+    compression: Compression,
+    serializer: LineSerializer,
+    config: RecorderConfig,
+    error_reporter: ErrorReporter,
+    self_metrics: SelfMetrics,
+    registry: Registry<Key, GenerationalAtomicStorage>,
+    recency: Recency<Key>,
+    limiter: SeriesLimiter,
     distribution_builder: DistributionBuilder,
+    distributions: DashMap<Key, Distribution>,
+    /// When the descriptions of metrics were last written.
+    metadata_written: Mutex<Option<Instant>>,
 }
 
 impl InfluxRecorder {
-    pub fn new(client: Client, request: RequestBuilder) -> InfluxRecorder {
-        let quantiles = parse_quantiles(&[0.0, 0.5, 0.9, 0.95, 0.99, 0.999, 1.0]);
+    pub fn new(
+        client: Client,
+        request: RequestBuilder,
+        signin: Option<RequestBuilder>,
+        precision: Precision,
+        compression: Compression,
+        config: RecorderConfig,
+    ) -> InfluxRecorder {
+        Self::with_clock(
+            client,
+            request,
+            signin,
+            precision,
+            compression,
+            config,
+            Clock::new(),
+        )
+    }
+
+    /// Creates a recorder whose idle timeout is measured with `clock`.
+    pub(crate) fn with_clock(
+        client: Client,
+        request: RequestBuilder,
+        signin: Option<RequestBuilder>,
+        precision: Precision,
+        compression: Compression,
+        config: RecorderConfig,
+        clock: Clock,
+    ) -> InfluxRecorder {
+        let distribution_builder = DistributionBuilder::new(
+            parse_quantiles(&config.quantiles),
+            config.buckets.clone(),
+            config.bucket_overrides.clone(),
+            config
+                .quantile_overrides
+                .iter()
+                .map(|(matcher, quantiles)| (matcher.clone(), parse_quantiles(quantiles)))
+                .collect(),
+        );
+        let error_reporter =
+            ErrorReporter::new(config.error_handler.clone(), config.error_log_interval);
+        let registry = Registry::new(GenerationalStorage::new(AtomicStorage));
+        let recency = Recency::new(clock, config.idle_timeout_mask, config.idle_timeout);
+        let self_metrics = SelfMetrics::new(&registry, config.self_metrics_prefix.as_deref());
+        let limiter = SeriesLimiter::new(&config);
         let inner = Inner {
             client,
             request,
-            registry: Registry::new(AtomicStorage),
-            distribution_builder: DistributionBuilder::new(quantiles, None),
+            signin,
+            signed_in: AtomicBool::new(false),
+            compression,
+            serializer: LineSerializer::new(
+                LineFormat {
+                    precision,
+                    unsigned_integers: config.unsigned_integers,
+                    non_finite_floats: config.non_finite_floats.clone(),
+                },
+                &config,
+            ),
+            config,
+            error_reporter,
+            self_metrics,
+            registry,
+            recency,
+            limiter,
+            distribution_builder,
+            distributions: DashMap::new(),
+            metadata_written: Mutex::new(None),
         };
         InfluxRecorder {
             inner: Arc::new(inner),
         }
     }
 
-    async fn write_metrics(&self, metrics: String) -> Result<()> {
-        let response = self
-            .inner
-            .request
-            .try_clone()
+    /// Returns what every described metric was described with, sorted by name.
+    pub fn metadata(&self) -> Vec<Metadata> {
+        self.inner.serializer.metadata()
+    }
+
+    fn describe(
+        &self,
+        kind: MetricKind,
+        key: KeyName,
+        unit: Option<Unit>,
+        description: SharedString,
+    ) {
+        self.inner.serializer.describe(Metadata {
+            name: key.as_str().to_owned(),
+            kind,
+            unit,
+            description: description.into_owned(),
+        });
+    }
+
+    /// Whether the descriptions of metrics are due to be written, which they are until a flush
+    /// writing them succeeds and then again after the configured interval.
+    fn metadata_due(&self) -> bool {
+        self.inner
+            .metadata_written
+            .lock()
             .unwrap()
-            .body(metrics)
-            .send()
-            .await?;
+            .is_none_or(|written| written.elapsed() >= self.inner.config.metadata_interval)
+    }
+
+    /// Opens a session through the configured signin request, unless one is already open.
+    ///
+    /// The session cookie is kept by the client's cookie store and sent along with every write.
+    async fn signin(&self) -> Result<()> {
+        let Some(ref signin) = self.inner.signin else {
+            return Ok(());
+        };
+        if self.inner.signed_in.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        let request = signin.try_clone().unwrap().build()?;
+        let response = self.inner.client.execute(request).await?;
 
         let status = response.status();
-        let influx_api_response = response.json::<InfluxApiResponse>().await?;
+        if status.is_success() {
+            self.inner.signed_in.store(true, Ordering::Release);
+            return Ok(());
+        }
+
+        Err(response_error(response).await)
+    }
+
+    /// Collects every registered metric and writes it in batches bounded by the
+    /// [`RecorderConfig`] limits.
+    ///
+    /// All batches are attempted even if one of them fails, every failure is passed to the
+    /// configured error handler and logged, and the first one is returned.
+    ///
+    /// Every line of a flush carries the same timestamp, taken before reading the registry.
+    pub async fn flush(&self) -> Result<()> {
+        let timestamp = Timestamp::now();
+        let self_metrics = &self.inner.self_metrics;
+        self_metrics.flushes.increment(1);
+        self_metrics.set_registry_size(&self.inner.registry);
+
+        let metadata_measurement = self
+            .inner
+            .config
+            .metadata_measurement
+            .as_deref()
+            .filter(|_| self.metadata_due());
+        let lines = self.serialize(timestamp, metadata_measurement);
+        self_metrics
+            .dropped_values
+            .increment(lines.dropped_values as u64);
 
-        match status {
-            status if status.is_success() => Ok(()),
-            err if err.as_u16() == 401 => Err(InfluxError::AuthenticationError {
-                error: influx_api_response.message.unwrap(),
-            }),
-            err if err.as_u16() == 403 => Err(InfluxError::AuthorizationError {
-                error: influx_api_response.message.unwrap(),
-            }),
-            err if err.as_u16() == 413 => Err(InfluxError::ContentTooLarge {
-                error: influx_api_response.message.unwrap(),
-            }),
-            _status => {
-                todo!()
+        let mut result = Ok(());
+        for batch in batches(
+            &lines.ranges,
+            self.inner.config.max_batch_lines,
+            self.inner.config.max_batch_bytes,
+            |range| range.len(),
+        ) {
+            if let Err(err) = self.write_batch(&lines, batch).await {
+                self.inner.error_reporter.failure(&err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        match result {
+            Ok(()) => {
+                if metadata_measurement.is_some() {
+                    *self.inner.metadata_written.lock().unwrap() = Some(Instant::now());
+                }
+                self.inner.error_reporter.success()
             }
+            Err(_) => self_metrics.flush_failures.increment(1),
         }
+        result
     }
+
+    /// Writes every handle of the registry as a line carrying `timestamp`, except the ones that
+    /// were idle for longer than the configured timeout, followed by the descriptions of metrics
+    /// written to `metadata_measurement`, if any.
+    fn serialize(&self, timestamp: Timestamp, metadata_measurement: Option<&str>) -> Lines {
+        let registry = &self.inner.registry;
+        let recency = &self.inner.recency;
+        let mut writer = self.inner.serializer.writer(timestamp);
+        for (key, counter) in registry.get_counter_handles() {
+            if self.keep(MetricKind::Counter, &key, || {
+                recency.should_store_counter(&key, counter.get_generation(), registry)
+            }) {
+                writer.counter(&key, counter.get_inner().load(Ordering::Relaxed));
+            }
+        }
+        for (key, gauge) in registry.get_gauge_handles() {
+            if self.keep(MetricKind::Gauge, &key, || {
+                recency.should_store_gauge(&key, gauge.get_generation(), registry)
+            }) {
+                writer.gauge(
+                    &key,
+                    f64::from_bits(gauge.get_inner().load(Ordering::Relaxed)),
+                );
+            }
+        }
+        for (key, histogram) in registry.get_histogram_handles() {
+            if !self.keep(MetricKind::Histogram, &key, || {
+                recency.should_store_histogram(&key, histogram.get_generation(), registry)
+            }) {
+                continue;
+            }
+            let histogram = histogram.get_inner();
+            let builder = &self.inner.distribution_builder;
+            match self.inner.config.histogram_semantics {
+                HistogramSemantics::Cumulative => {
+                    let mut distribution = self
+                        .inner
+                        .distributions
+                        .entry(key.clone())
+                        .or_insert_with(|| builder.get_distribution(key.name()));
+                    histogram.clear_with(|samples| distribution.record_samples(samples));
+                    writer.distribution(&key, &distribution);
+                }
+                HistogramSemantics::Delta => {
+                    let mut distribution = builder.get_distribution(key.name());
+                    histogram.clear_with(|samples| distribution.record_samples(samples));
+                    writer.distribution(&key, &distribution);
+                }
+            }
+        }
+        if let Some(measurement) = metadata_measurement {
+            for metadata in self.inner.serializer.metadata() {
+                writer.metadata(measurement, &metadata);
+            }
+        }
+        writer.finish()
+    }
+
+    /// Returns the key the series `key` is registered under, if it is within the series limits or
+    /// folded into an overflow series.
+    ///
+    /// A series over the limits is counted as rejected the first time it is registered, the
+    /// metrics macros register it again on every update.
+    fn admit<'a>(&self, kind: MetricKind, key: &'a Key) -> Option<Cow<'a, Key>> {
+        let (admitted, first) = match self.inner.limiter.admit(kind, key) {
+            Admission::Admitted => return Some(Cow::Borrowed(key)),
+            Admission::Folded { key, first } => (Some(Cow::Owned(key)), first),
+            Admission::Dropped { first } => (None, first),
+        };
+        if first {
+            self.inner.self_metrics.rejected_series.increment(1);
+        }
+        admitted
+    }
+
+    /// Whether `key` is still written, which `recent` tells unless it is one of the exporter's own
+    /// metrics. What is kept about a key that is not written anymore is evicted.
+    fn keep(&self, kind: MetricKind, key: &Key, recent: impl FnOnce() -> bool) -> bool {
+        // The exporter holds on to its own handles, they would never be registered again.
+        if self.inner.self_metrics.owns(key) || recent() {
+            return true;
+        }
+        self.inner.distributions.remove(key);
+        self.inner.serializer.forget(key);
+        self.inner.limiter.release(kind, key);
+        false
+    }
+
+    /// Writes the `batch` of `lines` in a single request, halving the batch whenever the server
+    /// answers with 413 Content Too Large until it is accepted or down to a single line.
+    async fn write_batch(&self, lines: &Lines, batch: &[Range<usize>]) -> Result<()> {
+        let mut pending = vec![batch];
+        let mut result = Ok(());
+        while let Some(batch) = pending.pop() {
+            match self.write_metrics(lines.join(batch)).await {
+                Err(InfluxError::ContentTooLarge { .. }) if batch.len() > 1 => {
+                    let (first, second) = batch.split_at(batch.len() / 2);
+                    pending.push(second);
+                    pending.push(first);
+                }
+                Err(err) => {
+                    self.inner.self_metrics.dropped_batches.increment(1);
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                }
+                Ok(()) => self
+                    .inner
+                    .self_metrics
+                    .lines_written
+                    .increment(batch.len() as u64),
+            }
+        }
+        result
+    }
+
+    /// Writes `metrics`, retrying failed attempts according to the configured [`RetryPolicy`].
+    ///
+    /// A `Retry-After` delay sent by the server takes precedence over the backoff delay, up to the
+    /// policy's maximum delay.
+    ///
+    /// [`RetryPolicy`]: crate::retry::RetryPolicy
+    async fn write_metrics(&self, metrics: Bytes) -> Result<()> {
+        let (metrics, encoding) = self.inner.compression.encode(metrics);
+        let policy = &self.inner.config.retry_policy;
+        let mut delays = policy.delays();
+        loop {
+            let err = match self.try_write_metrics(metrics.clone(), encoding).await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if !policy.is_retryable(&err) {
+                return Err(err);
+            }
+            match delays.next() {
+                Some(delay) => {
+                    sleep(policy.delay(&err, delay)).await;
+                    self.inner.self_metrics.retries.increment(1);
+                }
+                None => return Err(err),
+            }
+        }
+    }
+
+    async fn try_write_metrics(&self, metrics: Bytes, encoding: Option<&str>) -> Result<()> {
+        self.signin().await?;
+
+        let mut request = self.inner.request.try_clone().unwrap();
+        if let Some(encoding) = encoding {
+            request = request.header(CONTENT_ENCODING, encoding);
+        }
+        let request = request.body(metrics).build()?;
+        let bytes = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map_or(0, |body| body.len());
+        let start = Instant::now();
+        let response = self.inner.client.execute(request).await;
+        let self_metrics = &self.inner.self_metrics;
+        self_metrics
+            .write_duration
+            .record(start.elapsed().as_secs_f64());
+        let response = response?;
+        // Only bodies that reached the server count as sent.
+        self_metrics.bytes_sent.increment(bytes as u64);
+
+        let status = response.status();
+        if status.is_success() {
+            // Writes answer with an empty 204 on InfluxDB 2.x, there is nothing to decode.
+            return Ok(());
+        }
+        if status.as_u16() == 401 {
+            // The session may have expired, sign in again on the next write.
+            self.inner.signed_in.store(false, Ordering::Release);
+        }
+        Err(response_error(response).await)
+    }
+}
+
+/// Reads an unsuccessful response into the [`InfluxError`] matching its status.
+async fn response_error(response: Response) -> InfluxError {
+    let status = response.status().as_u16();
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, SystemTime::now()));
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(err) => return err.into(),
+    };
+
+    // Not every error comes with a JSON body, e.g. the ones sent by a proxy in front of InfluxDB.
+    let influx_api_response = serde_json::from_str::<InfluxApiResponse>(&body).unwrap_or_default();
+    let error = ApiError {
+        status,
+        code: influx_api_response.code,
+        message: influx_api_response.message.or(influx_api_response.error),
+        line: influx_api_response.line,
+        op: influx_api_response.op,
+        body,
+    };
+    InfluxError::from_response(error, retry_after)
+}
+
+/// Parses a `Retry-After` value, either a number of seconds or an HTTP-date, into the delay to
+/// wait from `now`. A date in the past means retrying right away.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
 }
 
 impl Recorder for InfluxRecorder {
-    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
-        unimplemented!("InfluxDB ILP does not support descriptions.")
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(MetricKind::Counter, key, unit, description);
     }
 
-    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
-        unimplemented!("InfluxDB ILP does not support descriptions.")
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(MetricKind::Gauge, key, unit, description);
     }
 
-    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
-        unimplemented!("InfluxDB ILP does not support descriptions.")
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(MetricKind::Histogram, key, unit, description);
     }
 
     fn register_counter(&self, key: &Key) -> Counter {
-        self.inner
-            .registry
-            .get_or_create_counter(key, |counter| counter.to_owned().into())
+        match self.admit(MetricKind::Counter, key) {
+            Some(key) => self
+                .inner
+                .registry
+                .get_or_create_counter(&key, |counter| counter.to_owned().into()),
+            None => Counter::noop(),
+        }
     }
 
     fn register_gauge(&self, key: &Key) -> Gauge {
-        self.inner
-            .registry
-            .get_or_create_gauge(key, |gauge| gauge.to_owned().into())
+        match self.admit(MetricKind::Gauge, key) {
+            Some(key) => self
+                .inner
+                .registry
+                .get_or_create_gauge(&key, |gauge| gauge.to_owned().into()),
+            None => Gauge::noop(),
+        }
     }
 
     fn register_histogram(&self, key: &Key) -> Histogram {
-        self.inner
-            .registry
-            .get_or_create_histogram(key, |histogram| histogram.to_owned().into())
+        match self.admit(MetricKind::Histogram, key) {
+            Some(key) => self
+                .inner
+                .registry
+                .get_or_create_histogram(&key, |histogram| histogram.to_owned().into()),
+            None => Histogram::noop(),
+        }
     }
 }
 
-#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
 pub struct InfluxApiResponse {
+    code: Option<String>,
     message: Option<String>,
+    /// The error message sent by InfluxDB 1.x and 3.x.
+    error: Option<String>,
     op: Option<String>,
     err: Option<String>,
     line: Option<i32>,
     max_len: Option<i32>,
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+    use std::time::UNIX_EPOCH;
+
+    use metrics::Label;
+    use metrics_util::MetricKindMask;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use crate::client::*;
+    use crate::config::{InfluxV1ConfigBuilder, RecorderConfigBuilder, SeriesOverflow};
+    use crate::retry::{RetryPolicy, RetryPolicyBuilder};
+
+    /// Serves HTTP on a local port, answering every request with the status and body returned by
+    /// `respond` for the request body. Returns the endpoint and the bodies received so far.
+    async fn mock_server<F>(respond: F) -> (String, Arc<Mutex<Vec<String>>>)
+    where
+        F: Fn(&str) -> (u16, String) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(vec![]));
+        let respond = Arc::new(respond);
+
+        let bodies = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let bodies = bodies.clone();
+                let respond = respond.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut content_length = 0;
+                        let mut gzip = false;
+                        let mut line = String::new();
+                        loop {
+                            line.clear();
+                            if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                            if line == "\r\n" {
+                                break;
+                            }
+                            if let Some((name, value)) = line.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    content_length = value.trim().parse().unwrap();
+                                }
+                                if name.eq_ignore_ascii_case("content-encoding") {
+                                    gzip = value.trim() == "gzip";
+                                }
+                            }
+                        }
+
+                        let mut body = vec![0; content_length];
+                        stream.read_exact(&mut body).await.unwrap();
+                        let body = if gzip {
+                            decode_gzip(&body)
+                        } else {
+                            String::from_utf8(body).unwrap()
+                        };
+                        let (status, response) = respond(&body);
+                        bodies.lock().unwrap().push(body);
+
+                        let response = format!(
+                            "HTTP/1.1 {status} Status\r\ncontent-length: {}\r\n\r\n{response}",
+                            response.len()
+                        );
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        (endpoint, received)
+    }
+
+    #[cfg(feature = "gzip")]
+    fn decode_gzip(body: &[u8]) -> String {
+        use std::io::Read;
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(body)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        decoded
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    fn decode_gzip(_body: &[u8]) -> String {
+        panic!("gzip bodies are only sent with the gzip feature")
+    }
+
+    fn client(endpoint: String, recorder_config: RecorderConfig) -> InfluxClient {
+        let config = InfluxV1ConfigBuilder::default()
+            .endpoint(endpoint)
+            .db("metrics")
+            .build()
+            .unwrap();
+        InfluxClient::with_recorder_config(config, recorder_config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_flush_batches() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let recorder_config = RecorderConfigBuilder::default()
+            .max_batch_lines(2usize)
+            .build()
+            .unwrap();
+        let recorder = client(endpoint, recorder_config).recorder();
+
+        for index in 0..5 {
+            let key = Key::from_parts("requests", vec![Label::new("index", index.to_string())]);
+            recorder.register_counter(&key).increment(1);
+        }
+
+        assert!(recorder.flush().await.is_ok());
+        let received = received.lock().unwrap();
+        assert_eq!(3, received.len());
+        assert_eq!(
+            5,
+            received
+                .iter()
+                .map(|body| body.lines().count())
+                .sum::<usize>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flush_splits_content_too_large() {
+        let (endpoint, received) = mock_server(|body| {
+            if body.lines().count() > 1 {
+                (413, r#"{"message":"too large"}"#.to_owned())
+            } else {
+                (204, String::new())
+            }
+        })
+        .await;
+        let recorder = client(endpoint, RecorderConfig::default()).recorder();
+
+        for index in 0..3 {
+            let key = Key::from_parts("requests", vec![Label::new("index", index.to_string())]);
+            recorder.register_counter(&key).increment(1);
+        }
+
+        assert!(recorder.flush().await.is_ok());
+        let received = received.lock().unwrap();
+        // One rejected batch of 3, one rejected half of 2 and three accepted single lines.
+        assert_eq!(5, received.len());
+        assert_eq!(
+            3,
+            received
+                .iter()
+                .filter(|body| body.lines().count() == 1)
+                .count()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_retries() {
+        let attempts = Arc::new(Mutex::new(0));
+        let counter = attempts.clone();
+        let (endpoint, received) = mock_server(move |_| {
+            let mut attempts = counter.lock().unwrap();
+            *attempts += 1;
+            match *attempts {
+                1 => (503, "unavailable".to_owned()),
+                2 => (429, "slow down".to_owned()),
+                _ => (204, String::new()),
+            }
+        })
+        .await;
+        let retry_policy = RetryPolicyBuilder::default()
+            .max_attempts(3usize)
+            .base_delay(Duration::from_millis(1))
+            .build()
+            .unwrap();
+        let recorder_config = RecorderConfigBuilder::default()
+            .retry_policy(retry_policy)
+            .build()
+            .unwrap();
+        let client = client(endpoint, recorder_config);
+
+        assert!(client
+            .write(&Metric::new("test").field("value", 1))
+            .await
+            .is_ok());
+        assert_eq!(3, received.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_write_does_not_retry_bad_request() {
+        let (endpoint, received) =
+            mock_server(|_| (400, r#"{"message":"bad line"}"#.to_owned())).await;
+        let client = client(endpoint, RecorderConfig::default());
+
+        let result = client.write(&Metric::new("test").field("value", 1)).await;
+        match result {
+            Err(InfluxError::BadRequest { error }) => {
+                assert_eq!(400, error.status);
+                assert_eq!(Some("bad line".to_owned()), error.message);
+                assert_eq!(r#"{"message":"bad line"}"#, error.body);
+            }
+            _ => panic!("expected a bad request error"),
+        }
+        assert_eq!(1, received.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            parse_retry_after(" 120 ", now)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            parse_retry_after(&httpdate::fmt_http_date(now + Duration::from_secs(30)), now)
+        );
+        assert_eq!(
+            Some(Duration::ZERO),
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now)
+        );
+        assert_eq!(None, parse_retry_after("soon", now));
+    }
+
+    #[tokio::test]
+    async fn test_write_unexpected_status() {
+        let (endpoint, _) = mock_server(|_| (418, "teapot".to_owned())).await;
+        let client = client(endpoint, RecorderConfig::default());
+
+        let result = client.write(&Metric::new("test").field("value", 1)).await;
+        match result {
+            Err(InfluxError::ClientError { error }) => {
+                assert_eq!(418, error.status);
+                assert_eq!(None, error.message);
+                assert_eq!("teapot", error.body);
+            }
+            _ => panic!("expected a client error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flushes() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let client = client(endpoint, RecorderConfig::default());
+        let handle = client.start(Duration::from_secs(3600));
+
+        client
+            .recorder()
+            .register_counter(&Key::from_name("requests"))
+            .increment(1);
+
+        assert!(handle.shutdown().await.is_ok());
+        let received = received.lock().unwrap();
+        assert_eq!(1, received.len());
+        assert!(received[0].starts_with("requests value=1i "));
+    }
+
+    #[tokio::test]
+    async fn test_detach_keeps_reporting() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let client = client(endpoint, RecorderConfig::default());
+        client.start(Duration::from_millis(20)).detach();
+
+        client
+            .recorder()
+            .register_counter(&Key::from_name("requests"))
+            .increment(1);
+
+        // A stopped reporter would only flush once.
+        let flushes = async {
+            while received.lock().unwrap().len() < 2 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        assert!(tokio::time::timeout(Duration::from_secs(10), flushes)
+            .await
+            .is_ok());
+        assert!(received.lock().unwrap()[0].starts_with("requests value=1i "));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_resumes_panic() {
+        let handle = ReporterHandle {
+            shutdown: None,
+            task: Some(tokio::spawn(async { panic!("reporter panicked") })),
+        };
+
+        let result = tokio::spawn(handle.shutdown()).await;
+        assert!(result.is_err_and(|err| err.is_panic()));
+    }
+
+    #[tokio::test]
+    async fn test_flush_error_handler() {
+        let (endpoint, _) = mock_server(|_| (400, r#"{"message":"bad line"}"#.to_owned())).await;
+        let errors = Arc::new(Mutex::new(vec![]));
+        let handled = errors.clone();
+        let recorder_config = RecorderConfigBuilder::default()
+            .error_handler(move |err: &InfluxError| handled.lock().unwrap().push(err.to_string()))
+            .build()
+            .unwrap();
+        let recorder = client(endpoint, recorder_config).recorder();
+
+        recorder
+            .register_counter(&Key::from_name("requests"))
+            .increment(1);
+
+        assert!(recorder.flush().await.is_err());
+        assert_eq!(vec!["HTTP 400: bad line"], *errors.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bytes_sent_skips_connection_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let recorder_config = RecorderConfigBuilder::default()
+            .self_metrics_prefix(Some("exporter_".to_owned()))
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap();
+        let client = client(endpoint, recorder_config);
+
+        let result = client.write(&Metric::new("test").field("value", 1)).await;
+        assert!(matches!(result, Err(InfluxError::ConnectionError(_))));
+        let bytes_sent = client
+            .recorder()
+            .inner
+            .registry
+            .get_counter_handles()
+            .into_iter()
+            .find(|(key, _)| key.name() == "exporter_bytes_sent_total")
+            .map(|(_, counter)| counter.get_inner().load(Ordering::Relaxed));
+        assert_eq!(Some(0), bytes_sent);
+    }
+
+    #[tokio::test]
+    async fn test_self_metrics() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let recorder_config = RecorderConfigBuilder::default()
+            .self_metrics_prefix(Some("exporter_".to_owned()))
+            .build()
+            .unwrap();
+        let recorder = client(endpoint, recorder_config).recorder();
+
+        recorder
+            .register_counter(&Key::from_name("requests"))
+            .increment(1);
+
+        assert!(recorder.flush().await.is_ok());
+        assert!(recorder.flush().await.is_ok());
+
+        let received = received.lock().unwrap();
+        let lines = received[1].lines().collect::<Vec<&str>>();
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("exporter_flushes_total value=2i ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("exporter_flush_failures_total value=0i ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("exporter_retries_total value=0i ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("exporter_lines_written_total ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("exporter_registry_size,kind=counter value=1 ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("exporter_registry_size,kind=gauge value=0 ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("exporter_registry_size,kind=histogram value=0 ")));
+    }
+
+    #[tokio::test]
+    async fn test_flush_gauge() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let recorder = client(endpoint, RecorderConfig::default()).recorder();
+        let gauge = recorder.register_gauge(&Key::from_name("temperature"));
+
+        gauge.increment(2.5);
+        assert!(recorder.flush().await.is_ok());
+        gauge.decrement(4.0);
+        assert!(recorder.flush().await.is_ok());
+        gauge.set(21.25);
+        assert!(recorder.flush().await.is_ok());
+
+        let received = received.lock().unwrap();
+        assert!(received[0].starts_with("temperature value=2.5 "));
+        assert!(received[1].starts_with("temperature value=-1.5 "));
+        assert!(received[2].starts_with("temperature value=21.25 "));
+    }
+
+    #[tokio::test]
+    async fn test_flush_histogram_semantics() {
+        for (semantics, expected) in [
+            (HistogramSemantics::Cumulative, "latency sum=3,count=2i"),
+            (HistogramSemantics::Delta, "latency sum=2,count=1i"),
+        ] {
+            let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+            let recorder_config = RecorderConfigBuilder::default()
+                .buckets(Some(vec![1.0]))
+                .histogram_semantics(semantics)
+                .build()
+                .unwrap();
+            let recorder = client(endpoint, recorder_config).recorder();
+            let histogram = recorder.register_histogram(&Key::from_name("latency"));
+
+            histogram.record(1.0);
+            assert!(recorder.flush().await.is_ok());
+            histogram.record(2.0);
+            assert!(recorder.flush().await.is_ok());
+
+            let received = received.lock().unwrap();
+            assert!(received[1].starts_with(expected), "{}", received[1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_timestamps() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let config = InfluxV1ConfigBuilder::default()
+            .endpoint(endpoint)
+            .db("metrics")
+            .precision(Some(Precision::Seconds))
+            .build()
+            .unwrap();
+        let recorder = InfluxClient::new(config).unwrap().recorder();
+
+        recorder
+            .register_counter(&Key::from_name("requests"))
+            .increment(1);
+        recorder
+            .register_gauge(&Key::from_name("connections"))
+            .set(2.0);
+
+        let before = Timestamp::now().nanoseconds() / 1_000_000_000;
+        assert!(recorder.flush().await.is_ok());
+        let after = Timestamp::now().nanoseconds() / 1_000_000_000;
+
+        let received = received.lock().unwrap();
+        let metrics = crate::parser::parse(&received[0]).unwrap();
+        assert_eq!(2, metrics.len());
+        let timestamp = metrics[0].timestamp.unwrap();
+        assert!(metrics
+            .iter()
+            .all(|metric| metric.timestamp == Some(timestamp)));
+        assert!((before..=after).contains(&timestamp));
+    }
+
+    #[tokio::test]
+    async fn test_write_skips_empty_line() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let client = client(endpoint, RecorderConfig::default());
+
+        assert!(client
+            .write(&Metric::new("test").field("value", f64::NAN))
+            .await
+            .is_ok());
+        assert!(client.write(&Metric::new("test")).await.is_ok());
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_flush_drops_non_finite() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let recorder_config = RecorderConfigBuilder::default()
+            .self_metrics_prefix(Some("exporter_".to_owned()))
+            .build()
+            .unwrap();
+        let recorder = client(endpoint, recorder_config).recorder();
+
+        recorder
+            .register_gauge(&Key::from_name("load"))
+            .set(f64::NAN);
+
+        assert!(recorder.flush().await.is_ok());
+        assert!(recorder.flush().await.is_ok());
+
+        let received = received.lock().unwrap();
+        assert!(!received[0].contains("load"));
+        assert!(received[1]
+            .lines()
+            .any(|line| line.starts_with("exporter_dropped_values_total value=1i ")));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_write_gzip() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let config = InfluxV1ConfigBuilder::default()
+            .endpoint(endpoint)
+            .db("metrics")
+            .compression(Compression::Gzip { min_bytes: 64 })
+            .build()
+            .unwrap();
+        let recorder_config = RecorderConfigBuilder::default()
+            .self_metrics_prefix(Some("exporter_".to_owned()))
+            .build()
+            .unwrap();
+        let client = InfluxClient::with_recorder_config(config, recorder_config).unwrap();
+        let recorder = client.recorder();
+        for index in 0..100 {
+            recorder
+                .register_counter(&Key::from_parts(
+                    "requests",
+                    vec![Label::new("path", format!("/{index}"))],
+                ))
+                .increment(1);
+        }
+
+        assert!(recorder.flush().await.is_ok());
+        assert!(recorder.flush().await.is_ok());
+
+        let received = received.lock().unwrap();
+        assert!(received[0].contains("requests,path=/99 value=1i "));
+        let bytes_sent = received[1]
+            .lines()
+            .find_map(|line| line.strip_prefix("exporter_bytes_sent_total value="))
+            .and_then(|value| value.split_once('i'))
+            .map(|(value, _)| value.parse::<usize>().unwrap())
+            .unwrap();
+        assert!(bytes_sent < received[0].len() / 2);
+    }
+
+    #[tokio::test]
+    async fn test_describe() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let recorder_config = RecorderConfigBuilder::default()
+            .metadata_measurement(Some("metrics_metadata".to_owned()))
+            .build()
+            .unwrap();
+        let recorder = client(endpoint, recorder_config).recorder();
+
+        recorder.describe_counter(
+            KeyName::from("requests"),
+            Some(Unit::Count),
+            SharedString::from("Requests served"),
+        );
+        recorder.describe_gauge(
+            KeyName::from("connections"),
+            None,
+            SharedString::from("Open connections"),
+        );
+        recorder
+            .register_counter(&Key::from_name("requests"))
+            .increment(1);
+
+        assert_eq!(
+            vec![
+                Metadata {
+                    name: "connections".to_owned(),
+                    kind: MetricKind::Gauge,
+                    unit: None,
+                    description: "Open connections".to_owned(),
+                },
+                Metadata {
+                    name: "requests".to_owned(),
+                    kind: MetricKind::Counter,
+                    unit: Some(Unit::Count),
+                    description: "Requests served".to_owned(),
+                },
+            ],
+            recorder.metadata()
+        );
+
+        assert!(recorder.flush().await.is_ok());
+        assert!(recorder.flush().await.is_ok());
+
+        let received = received.lock().unwrap();
+        let metadata_lines = |body: &str| {
+            body.lines()
+                .filter(|line| line.starts_with("metrics_metadata,"))
+                .count()
+        };
+        assert_eq!(2, metadata_lines(&received[0]));
+        assert!(received[0].contains(
+            "metrics_metadata,name=requests,kind=counter description=\"Requests served\",unit=\"count\" "
+        ));
+        // The descriptions are only written again once the interval has passed.
+        assert_eq!(0, metadata_lines(&received[1]));
+    }
+
+    #[tokio::test]
+    async fn test_describe_after_failure() {
+        let failed = AtomicBool::new(false);
+        let (endpoint, received) =
+            mock_server(move |_| match failed.swap(true, Ordering::Relaxed) {
+                false => (400, r#"{"message":"bad line"}"#.to_owned()),
+                true => (204, String::new()),
+            })
+            .await;
+        let recorder_config = RecorderConfigBuilder::default()
+            .metadata_measurement(Some("metrics_metadata".to_owned()))
+            .build()
+            .unwrap();
+        let recorder = client(endpoint, recorder_config).recorder();
+
+        recorder.describe_counter(
+            KeyName::from("requests"),
+            None,
+            SharedString::from("Requests served"),
+        );
+        recorder
+            .register_counter(&Key::from_name("requests"))
+            .increment(1);
+
+        assert!(recorder.flush().await.is_err());
+        assert!(recorder.flush().await.is_ok());
+        assert!(recorder.flush().await.is_ok());
+
+        let received = received.lock().unwrap();
+        let metadata_lines = |body: &str| {
+            body.lines()
+                .filter(|line| line.starts_with("metrics_metadata,"))
+                .count()
+        };
+        // The descriptions are written again until a flush succeeds.
+        assert_eq!(1, metadata_lines(&received[0]));
+        assert_eq!(1, metadata_lines(&received[1]));
+        assert_eq!(0, metadata_lines(&received[2]));
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let recorder_config = RecorderConfigBuilder::default()
+            .self_metrics_prefix(Some("exporter_".to_owned()))
+            .idle_timeout(Some(Duration::from_millis(50)))
+            .idle_timeout_mask(MetricKindMask::COUNTER | MetricKindMask::HISTOGRAM)
+            .build()
+            .unwrap();
+        let config = InfluxV1ConfigBuilder::default()
+            .endpoint(endpoint)
+            .db("metrics")
+            .build()
+            .unwrap();
+        let client = Client::new();
+        let (clock, time) = Clock::mock();
+        let recorder = InfluxRecorder::with_clock(
+            client.clone(),
+            config.request(&client),
+            config.signin(&client),
+            config.precision(),
+            config.compression(),
+            recorder_config,
+            clock,
+        );
+
+        let key =
+            |path: &str| Key::from_parts("requests", vec![Label::new("path", path.to_owned())]);
+        recorder.register_counter(&key("/old")).increment(1);
+        // Sharing the prefix of the exporter's own metrics does not keep a metric around.
+        recorder
+            .register_counter(&Key::from_name("exporter_jobs_total"))
+            .increment(1);
+        let active = recorder.register_counter(&key("/active"));
+        active.increment(1);
+        recorder
+            .register_gauge(&Key::from_name("temperature"))
+            .set(21.0);
+
+        assert!(recorder.flush().await.is_ok());
+        time.increment(Duration::from_millis(100));
+        active.increment(1);
+        assert!(recorder.flush().await.is_ok());
+        time.increment(Duration::from_millis(100));
+        assert!(recorder.flush().await.is_ok());
+
+        let received = received.lock().unwrap();
+        assert!(received[0].contains("requests,path=/old value=1i "));
+        assert!(received[0].contains("requests,path=/active value=1i "));
+        // The old path was idle for longer than the timeout, the active one was updated.
+        assert!(!received[1].contains("requests,path=/old "));
+        assert!(received[1].contains("requests,path=/active value=2i "));
+        assert!(!received[2].contains("requests,"));
+        assert!(received[0].contains("exporter_jobs_total value=1i "));
+        assert!(!received[2].contains("exporter_jobs_total"));
+        // Gauges are out of the mask, and the exporter's own metrics are always written.
+        assert!(received[2].contains("temperature value=21 "));
+        assert!(received[2].contains("exporter_flushes_total value=3i "));
+        assert_eq!(
+            0,
+            recorder
+                .inner
+                .registry
+                .get_counter_handles()
+                .keys()
+                .filter(|key| key.name() == "requests")
+                .count()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_series() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let recorder_config = RecorderConfigBuilder::default()
+            .self_metrics_prefix(Some("exporter_".to_owned()))
+            .max_series_per_metric(Some(2))
+            .series_overflow(SeriesOverflow::Fold)
+            .build()
+            .unwrap();
+        let recorder = client(endpoint, recorder_config).recorder();
+
+        let key =
+            |path: &str| Key::from_parts("requests", vec![Label::new("path", path.to_owned())]);
+        for path in ["/a", "/b", "/c", "/d", "/a", "/c"] {
+            recorder.register_counter(&key(path)).increment(1);
+        }
+        assert!(recorder.flush().await.is_ok());
+
+        let received = received.lock().unwrap();
+        assert!(received[0].contains("requests,path=/a value=2i "));
+        assert!(received[0].contains("requests,path=/b value=1i "));
+        assert!(received[0].contains("requests,path=__overflow__ value=3i "));
+        assert!(!received[0].contains("requests,path=/c "));
+        assert!(received[0].contains("exporter_rejected_series_total value=2i "));
+    }
+
+    #[tokio::test]
+    async fn test_max_series_drop() {
+        let (endpoint, received) = mock_server(|_| (204, String::new())).await;
+        let recorder_config = RecorderConfigBuilder::default()
+            .self_metrics_prefix(Some("exporter_".to_owned()))
+            .max_series(Some(1))
+            .build()
+            .unwrap();
+        let recorder = client(endpoint, recorder_config).recorder();
+
+        recorder
+            .register_gauge(&Key::from_name("temperature"))
+            .set(21.0);
+        recorder
+            .register_gauge(&Key::from_name("humidity"))
+            .set(40.0);
+        recorder
+            .register_histogram(&Key::from_name("latency"))
+            .record(1.0);
+        assert!(recorder.flush().await.is_ok());
+
+        let received = received.lock().unwrap();
+        assert!(received[0].contains("temperature value=21 "));
+        assert!(!received[0].contains("humidity"));
+        assert!(!received[0].contains("latency"));
+        // The exporter's own metrics are not limited.
+        assert!(received[0].contains("exporter_rejected_series_total value=2i "));
+    }
+}
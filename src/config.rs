@@ -1,9 +1,18 @@
 use derivative::Derivative;
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
 
+use bytes::Bytes;
 use derive_builder::Builder;
+use metrics_util::{MetricKindMask, Quantile};
+use reqwest::header::AUTHORIZATION;
 use reqwest::{Client, RequestBuilder};
 
+use crate::error::{ErrorHandler, InfluxError};
+use crate::retry::RetryPolicy;
+
 #[derive(Debug, Default, Clone, PartialEq, PartialOrd)]
 pub enum Consistency {
     #[default]
@@ -50,9 +59,209 @@ impl Display for Precision {
     }
 }
 
+/// How the name of a metric maps to the measurement and fields it is written to.
+///
+/// A measurement starting with `#` is written as `\#`, see [`Metric::new`].
+///
+/// [`Metric::new`]: crate::metric::Metric::new
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd)]
+pub enum MeasurementNaming {
+    /// Every metric is a measurement of its own, e.g. `http.requests.total value=1i`.
+    #[default]
+    PerMetric,
+    /// The name is split at its first `.` into the measurement and the field, the remaining dots
+    /// becoming underscores, e.g. `http requests_total=1i`. The fields of histograms are prefixed
+    /// with that field, e.g. `http latency_sum=1.5,latency_count=2i`.
+    ///
+    /// Names without a `.` are written like [`MeasurementNaming::PerMetric`] does.
+    Split,
+    /// Every metric is written to `measurement`, with its name in the `tag` tag, e.g.
+    /// `app,name=http.requests.total value=1i`.
+    ///
+    /// A label with the same name as `tag` is written with a `_label` suffix, e.g.
+    /// `app,name=http.requests.total,name_label=value value=1i`. A global tag cannot have that
+    /// name.
+    Single { measurement: String, tag: String },
+}
+
+/// Where the unit a metric was described with is written.
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd)]
+pub enum UnitPlacement {
+    /// The unit is not written.
+    #[default]
+    Omit,
+    /// The unit is written in the given tag, e.g. `latency,unit=seconds sum=1.5`.
+    Tag(String),
+    /// The unit is appended to every field, e.g. `latency sum_seconds=1.5`.
+    FieldSuffix,
+}
+
+/// What happens to the label sets of a metric over the series limits.
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd)]
+pub enum SeriesOverflow {
+    /// Their updates are dropped.
+    #[default]
+    Drop,
+    /// Their updates go to a single series of the metric, whose labels all have the
+    /// `__overflow__` value. That series does not count toward the limits.
+    Fold,
+}
+
+/// What happens to `NaN` and infinite float values, which line protocol cannot represent.
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd)]
+pub enum NonFiniteFloats {
+    /// Leaves the field out, the line is dropped if no field is left.
+    #[default]
+    DropField,
+    /// Drops the whole line.
+    DropLine,
+    /// Replaces infinities with the largest finite value of the same sign and `NaN` with zero.
+    Clamp,
+    /// Replaces every non-finite value with the given one.
+    Replace(f64),
+}
+
+impl NonFiniteFloats {
+    /// Returns the value written in place of the non-finite `value`, if any.
+    pub(crate) fn replace(&self, value: f64) -> Option<f64> {
+        match self {
+            NonFiniteFloats::DropField | NonFiniteFloats::DropLine => None,
+            NonFiniteFloats::Clamp if value.is_nan() => Some(0.0),
+            NonFiniteFloats::Clamp => Some(value.clamp(f64::MIN, f64::MAX)),
+            NonFiniteFloats::Replace(replacement) => Some(*replacement),
+        }
+    }
+
+    /// Fails when the replacement value is not finite itself.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        match self {
+            NonFiniteFloats::Replace(replacement) if !replacement.is_finite() => {
+                Err("the replacement of non-finite floats must be finite".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How histogram values accumulate from one flush to the next.
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd)]
+pub enum HistogramSemantics {
+    /// Every flush reports everything recorded since the histogram was registered, summary
+    /// quantiles cover their rolling window.
+    #[default]
+    Cumulative,
+    /// Every flush only reports what was recorded since the previous flush.
+    Delta,
+}
+
+/// Matches metric names for per-metric settings.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd)]
+pub enum Matcher {
+    /// Matches the entire metric name.
+    Full(String),
+    /// Matches the beginning of the metric name.
+    Prefix(String),
+    /// Matches the end of the metric name.
+    Suffix(String),
+}
+
+impl Matcher {
+    /// Checks if the given name matches.
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Matcher::Full(full) => name == full,
+            Matcher::Prefix(prefix) => name.starts_with(prefix),
+            Matcher::Suffix(suffix) => name.ends_with(suffix),
+        }
+    }
+}
+
+/// How the quantile fields of a summary are named.
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd)]
+pub enum QuantileNaming {
+    /// Percentile labels such as `p50`, `p99` and `p999`.
+    #[default]
+    Percentile,
+    /// The quantile itself such as `0.5`, `0.99` and `0.999`.
+    Quantile,
+}
+
+impl QuantileNaming {
+    pub(crate) fn field<'a>(&self, quantile: &'a Quantile) -> Cow<'a, str> {
+        match self {
+            QuantileNaming::Percentile => Cow::Borrowed(quantile.label()),
+            QuantileNaming::Quantile => Cow::Owned(quantile.value().to_string()),
+        }
+    }
+}
+
+impl Precision {
+    /// Converts nanoseconds to this precision, truncating what does not fit.
+    pub(crate) fn convert_nanoseconds(&self, nanoseconds: i128) -> i128 {
+        let divisor = match self {
+            Precision::Nanoseconds => 1,
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Seconds => 1_000_000_000,
+            Precision::Minutes => 60 * 1_000_000_000,
+            Precision::Hours => 60 * 60 * 1_000_000_000,
+        };
+        // Times before the epoch are rounded down too.
+        nanoseconds.div_euclid(divisor)
+    }
+}
+
+/// Compression of write request bodies, which InfluxDB accepts through `Content-Encoding`.
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Gzips bodies of at least `min_bytes` bytes, smaller ones are sent as is.
+    #[cfg(feature = "gzip")]
+    Gzip { min_bytes: usize },
+}
+
+impl Compression {
+    /// Compresses `body` if needed, returning it with the `Content-Encoding` to send it with.
+    pub(crate) fn encode(&self, body: Bytes) -> (Bytes, Option<&'static str>) {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip { min_bytes } if body.len() >= *min_bytes => {
+                use std::io::Write;
+
+                let mut encoder = flate2::write::GzEncoder::new(
+                    Vec::with_capacity(body.len() / 4),
+                    flate2::Compression::default(),
+                );
+                let compressed = encoder
+                    .write_all(&body)
+                    .and_then(|_| encoder.finish())
+                    .expect("writing to a Vec cannot fail");
+                (compressed.into(), Some("gzip"))
+            }
+            _ => (body, None),
+        }
+    }
+}
+
 pub trait InfluxConfig {
     fn parameters(&self) -> Vec<(&str, String)>;
     fn request(&self, client: &Client) -> RequestBuilder;
+
+    /// The precision timestamps are written in.
+    fn precision(&self) -> Precision {
+        Precision::Nanoseconds
+    }
+
+    /// Returns the request used to open a session before writing, if the config needs one.
+    fn signin(&self, _client: &Client) -> Option<RequestBuilder> {
+        None
+    }
+
+    /// How write request bodies are compressed.
+    fn compression(&self) -> Compression {
+        Compression::None
+    }
 }
 
 #[derive(Derivative)]
@@ -69,6 +278,7 @@ pub struct InfluxV1Config {
     pub(crate) retention_policy: Option<String>,
     pub(crate) precision: Option<Precision>,
     pub(crate) consistency: Option<Consistency>,
+    pub(crate) compression: Compression,
 }
 
 impl InfluxConfig for InfluxV1Config {
@@ -95,6 +305,14 @@ impl InfluxConfig for InfluxV1Config {
             .post(format!("{}/write", self.endpoint))
             .query(&self.parameters())
     }
+
+    fn precision(&self) -> Precision {
+        self.precision.clone().unwrap_or_default()
+    }
+
+    fn compression(&self) -> Compression {
+        self.compression.clone()
+    }
 }
 
 #[derive(Derivative)]
@@ -107,21 +325,342 @@ pub struct InfluxV2Config {
     pub(crate) bucket: String,
     pub(crate) org: String,
     pub(crate) precision: Precision,
+    #[derivative(Debug = "ignore")]
+    pub(crate) token: Option<String>,
     pub(crate) username: Option<String>,
     #[derivative(Debug = "ignore")]
     pub(crate) password: Option<String>,
+    pub(crate) compression: Compression,
 }
 
 impl InfluxConfig for InfluxV2Config {
     fn parameters(&self) -> Vec<(&str, String)> {
-        todo!()
+        vec![
+            ("org", self.org.clone()),
+            ("bucket", self.bucket.clone()),
+            ("precision", self.precision.to_string()),
+        ]
     }
 
-    fn request(&self, _client: &Client) -> RequestBuilder {
-        todo!()
+    fn request(&self, client: &Client) -> RequestBuilder {
+        let request = client
+            .post(format!("{}/api/v2/write", self.endpoint))
+            .query(&self.parameters());
+        match self.token {
+            Some(ref token) => request.header(AUTHORIZATION, format!("Token {token}")),
+            None => request,
+        }
+    }
+
+    fn precision(&self) -> Precision {
+        self.precision.clone()
+    }
+
+    fn compression(&self) -> Compression {
+        self.compression.clone()
+    }
+
+    fn signin(&self, client: &Client) -> Option<RequestBuilder> {
+        if self.token.is_some() {
+            return None;
+        }
+        self.username.as_ref().map(|username| {
+            client
+                .post(format!("{}/api/v2/signin", self.endpoint))
+                .basic_auth(username, self.password.as_ref())
+        })
     }
 }
 
+#[derive(Derivative)]
+#[derivative(Debug)]
+#[derive(Default, Clone, PartialEq, PartialOrd, Builder)]
+#[builder(setter(into))]
+#[builder(default)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct InfluxV3Config {
+    pub(crate) endpoint: String,
+    pub(crate) db: String,
+    #[derivative(Debug = "ignore")]
+    pub(crate) token: Option<String>,
+    pub(crate) precision: Option<Precision>,
+    pub(crate) accept_partial: Option<bool>,
+    pub(crate) no_sync: Option<bool>,
+}
+
+impl InfluxV3ConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        match self.precision {
+            Some(Some(ref precision)) if v3_precision(precision).is_none() => Err(format!(
+                "precision {precision} is not supported by InfluxDB 3"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Maps a [`Precision`] to the name used by the InfluxDB 3 `write_lp` API.
+fn v3_precision(precision: &Precision) -> Option<&'static str> {
+    match precision {
+        Precision::Nanoseconds => Some("nanosecond"),
+        Precision::Microseconds => Some("microsecond"),
+        Precision::Milliseconds => Some("millisecond"),
+        Precision::Seconds => Some("second"),
+        Precision::Minutes | Precision::Hours => None,
+    }
+}
+
+impl InfluxConfig for InfluxV3Config {
+    fn parameters(&self) -> Vec<(&str, String)> {
+        vec![
+            Some(("db", self.db.clone())),
+            self.precision
+                .as_ref()
+                .and_then(v3_precision)
+                .map(|p| ("precision", p.to_owned())),
+            self.accept_partial
+                .map(|ap| ("accept_partial", ap.to_string())),
+            self.no_sync.map(|ns| ("no_sync", ns.to_string())),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<(&str, String)>>()
+    }
+
+    fn request(&self, client: &Client) -> RequestBuilder {
+        let request = client
+            .post(format!("{}/api/v3/write_lp", self.endpoint))
+            .query(&self.parameters());
+        match self.token {
+            Some(ref token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    fn precision(&self) -> Precision {
+        self.precision.clone().unwrap_or_default()
+    }
+}
+
+/// Settings for how the recorder batches and delivers metrics, independent of the server version.
+#[derive(Derivative)]
+#[derivative(Debug)]
+#[derive(Clone, Builder)]
+#[builder(setter(into))]
+#[builder(default)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct RecorderConfig {
+    /// Maximum number of lines sent in a single write request.
+    pub(crate) max_batch_lines: usize,
+    /// Maximum size in bytes of a single write request body.
+    pub(crate) max_batch_bytes: usize,
+    /// How failed writes are retried.
+    pub(crate) retry_policy: RetryPolicy,
+    /// Called with every error hit while flushing in the background.
+    #[derivative(Debug = "ignore")]
+    #[builder(setter(custom))]
+    pub(crate) error_handler: Option<ErrorHandler>,
+    /// Minimum time between two logged flush errors, the ones in between are only counted.
+    pub(crate) error_log_interval: Duration,
+    /// Prefix of the metrics the exporter reports about itself, e.g. `metrics_influxdb_`.
+    ///
+    /// The exporter does not report anything about itself when unset.
+    pub(crate) self_metrics_prefix: Option<String>,
+    /// Whether unsigned values such as counters are written with the `u` suffix, see
+    /// [`LineFormatBuilder::unsigned_integers`].
+    ///
+    /// [`LineFormatBuilder::unsigned_integers`]: crate::metric::LineFormatBuilder::unsigned_integers
+    pub(crate) unsigned_integers: bool,
+    /// See [`NonFiniteFloats`].
+    pub(crate) non_finite_floats: NonFiniteFloats,
+    /// How the quantile fields of summaries are named.
+    pub(crate) quantile_naming: QuantileNaming,
+    /// Quantiles written for histograms that are reported as summaries.
+    pub(crate) quantiles: Vec<f64>,
+    /// Buckets for histograms, which are reported as summaries when unset.
+    pub(crate) buckets: Option<Vec<f64>>,
+    /// Whether histograms report cumulative or per-flush values.
+    pub(crate) histogram_semantics: HistogramSemantics,
+    /// Per-metric buckets, see [`RecorderConfigBuilder::set_buckets_for_metric`].
+    #[builder(setter(custom))]
+    pub(crate) bucket_overrides: Vec<(Matcher, Vec<f64>)>,
+    /// Per-metric quantiles, see [`RecorderConfigBuilder::set_quantiles_for_metric`].
+    #[builder(setter(custom))]
+    pub(crate) quantile_overrides: Vec<(Matcher, Vec<f64>)>,
+    /// Prefix prepended to the name of every metric, e.g. `myapp_`.
+    pub(crate) prefix: Option<String>,
+    /// How the name of a metric maps to the measurement and fields it is written to.
+    pub(crate) measurement_naming: MeasurementNaming,
+    /// Whether counters and gauges sharing their measurement and tags are written as a single
+    /// line with a field each, e.g. `process cpu=0.5,mem=1024` with
+    /// [`MeasurementNaming::Split`].
+    pub(crate) group_fields: bool,
+    /// Where the unit a metric was described with is written.
+    pub(crate) unit_placement: UnitPlacement,
+    /// Measurement the descriptions of metrics are written to, with the name and kind of the
+    /// metric as tags and its description and unit as fields.
+    ///
+    /// The name carries the [`prefix`](RecorderConfigBuilder::prefix). When the metric is not
+    /// written to a measurement of that name, the `measurement` tag, and the `field` tag for
+    /// [`MeasurementNaming::Split`], tell where its lines are.
+    ///
+    /// Descriptions are not written when unset.
+    pub(crate) metadata_measurement: Option<String>,
+    /// Minimum time between two writes of the descriptions, which happen along with a flush.
+    pub(crate) metadata_interval: Duration,
+    /// Time after which a metric that was not updated stops being written and is removed from the
+    /// registry. Metrics are written for as long as the recorder lives when unset.
+    pub(crate) idle_timeout: Option<Duration>,
+    /// Kinds of metrics the idle timeout applies to, e.g. `MetricKindMask::COUNTER |
+    /// MetricKindMask::HISTOGRAM` to keep gauges forever.
+    pub(crate) idle_timeout_mask: MetricKindMask,
+    /// Maximum number of distinct label sets of a single metric, unlimited when unset.
+    pub(crate) max_series_per_metric: Option<usize>,
+    /// Maximum number of distinct label sets over all metrics, unlimited when unset.
+    ///
+    /// Neither the exporter's own metrics nor the series that [`SeriesOverflow::Fold`] folds
+    /// label sets into count toward it.
+    pub(crate) max_series: Option<usize>,
+    /// What happens to the label sets over these limits.
+    pub(crate) series_overflow: SeriesOverflow,
+    /// Tags added to every line, see [`RecorderConfigBuilder::global_tag`].
+    #[builder(setter(custom))]
+    pub(crate) global_tags: Vec<(String, String)>,
+}
+
+impl RecorderConfigBuilder {
+    /// Sets the callback invoked with every error hit while flushing in the background.
+    pub fn error_handler<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(&InfluxError) + Send + Sync + 'static,
+    {
+        self.error_handler = Some(Some(Arc::new(handler)));
+        self
+    }
+
+    /// Reports histograms whose name matches `matcher` with the given buckets, regardless of the
+    /// global buckets and quantiles.
+    ///
+    /// Matchers are checked in the order they were added, the first match wins.
+    pub fn set_buckets_for_metric(&mut self, matcher: Matcher, buckets: &[f64]) -> &mut Self {
+        self.bucket_overrides
+            .get_or_insert_with(Vec::new)
+            .push((matcher, buckets.to_vec()));
+        self
+    }
+
+    /// Reports histograms whose name matches `matcher` as summaries with the given quantiles,
+    /// regardless of the global buckets and quantiles.
+    ///
+    /// Matchers are checked in the order they were added, the first match wins.
+    pub fn set_quantiles_for_metric(&mut self, matcher: Matcher, quantiles: &[f64]) -> &mut Self {
+        self.quantile_overrides
+            .get_or_insert_with(Vec::new)
+            .push((matcher, quantiles.to_vec()));
+        self
+    }
+
+    /// Adds a tag to every line written by the recorder, replacing a global tag of the same name.
+    ///
+    /// A label of the same name set on the metric takes priority over the global tag.
+    pub fn global_tag(&mut self, tag: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        let tag = tag.into();
+        let global_tags = self.global_tags.get_or_insert_with(Vec::new);
+        global_tags.retain(|(name, _)| *name != tag);
+        global_tags.push((tag, value.into()));
+        self
+    }
+
+    /// Adds a global tag holding the value of the environment `variable`, nothing is added when
+    /// it is unset or empty.
+    pub fn global_tag_from_env(&mut self, tag: impl Into<String>, variable: &str) -> &mut Self {
+        match std::env::var(variable) {
+            Ok(value) if !value.is_empty() => self.global_tag(tag, value),
+            _ => self,
+        }
+    }
+
+    /// Adds a global tag holding the hostname of the machine, nothing is added when it cannot be
+    /// found.
+    pub fn global_tag_from_hostname(&mut self, tag: impl Into<String>) -> &mut Self {
+        match gethostname::gethostname().into_string() {
+            Ok(hostname) if !hostname.trim().is_empty() => {
+                self.global_tag(tag, hostname.trim().to_owned())
+            }
+            _ => self,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let empty_buckets = matches!(self.buckets, Some(Some(ref buckets)) if buckets.is_empty())
+            || self
+                .bucket_overrides
+                .iter()
+                .flatten()
+                .any(|(_, buckets)| buckets.is_empty());
+        if empty_buckets {
+            return Err("buckets must not be empty".to_owned());
+        }
+
+        let empty_quantiles = matches!(self.quantiles, Some(ref quantiles) if quantiles.is_empty())
+            || self
+                .quantile_overrides
+                .iter()
+                .flatten()
+                .any(|(_, quantiles)| quantiles.is_empty());
+        if empty_quantiles {
+            return Err("quantiles must not be empty".to_owned());
+        }
+
+        if let Some(ref non_finite_floats) = self.non_finite_floats {
+            non_finite_floats.validate()?;
+        }
+
+        if let (Some(MeasurementNaming::Single { tag, .. }), Some(global_tags)) =
+            (&self.measurement_naming, &self.global_tags)
+        {
+            if global_tags.iter().any(|(global_tag, _)| global_tag == tag) {
+                return Err(format!("the global tag {tag:?} is used for metric names"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        RecorderConfig {
+            max_batch_lines: 5_000,
+            max_batch_bytes: 1024 * 1024,
+            retry_policy: RetryPolicy::default(),
+            error_handler: None,
+            error_log_interval: Duration::from_secs(60),
+            self_metrics_prefix: None,
+            unsigned_integers: false,
+            non_finite_floats: NonFiniteFloats::default(),
+            quantile_naming: QuantileNaming::default(),
+            quantiles: vec![0.0, 0.5, 0.9, 0.95, 0.99, 0.999, 1.0],
+            buckets: None,
+            histogram_semantics: HistogramSemantics::default(),
+            bucket_overrides: vec![],
+            quantile_overrides: vec![],
+            prefix: None,
+            measurement_naming: MeasurementNaming::default(),
+            group_fields: false,
+            unit_placement: UnitPlacement::default(),
+            metadata_measurement: None,
+            metadata_interval: Duration::from_secs(60 * 60),
+            idle_timeout: None,
+            idle_timeout_mask: MetricKindMask::ALL,
+            max_series_per_metric: None,
+            max_series: None,
+            series_overflow: SeriesOverflow::default(),
+            global_tags: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
 mod test {
     use crate::config::*;
 
@@ -135,6 +674,7 @@ mod test {
             retention_policy: Some("test".to_owned()),
             precision: Some(Precision::Hours),
             consistency: Some(Consistency::All),
+            compression: Compression::None,
         };
 
         let expected_params = vec![
@@ -161,4 +701,227 @@ mod test {
         assert_eq!(expected_config, config);
         assert_eq!(expected_params, config.parameters());
     }
+
+    #[test]
+    fn test_v2_config() {
+        let expected_config = InfluxV2Config {
+            endpoint: "http://localhost:8086".to_owned(),
+            bucket: "metrics".to_owned(),
+            org: "org".to_owned(),
+            precision: Precision::Seconds,
+            token: Some("token".to_owned()),
+            username: None,
+            password: None,
+            compression: Compression::None,
+        };
+
+        let expected_params = vec![
+            ("org", "org".to_owned()),
+            ("bucket", "metrics".to_owned()),
+            ("precision", "s".to_owned()),
+        ];
+
+        let config = InfluxV2ConfigBuilder::default()
+            .endpoint("http://localhost:8086")
+            .bucket("metrics")
+            .org("org")
+            .precision(Precision::Seconds)
+            .token(Some("token".to_string()))
+            .build();
+
+        assert!(config.is_ok());
+        let config = config.unwrap();
+        assert_eq!(expected_config, config);
+        assert_eq!(expected_params, config.parameters());
+
+        let client = Client::new();
+        assert!(config.signin(&client).is_none());
+
+        let request = config.request(&client).build().unwrap();
+        assert_eq!(
+            "http://localhost:8086/api/v2/write?org=org&bucket=metrics&precision=s",
+            request.url().as_str()
+        );
+        assert_eq!("Token token", request.headers()[AUTHORIZATION]);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip_compression() {
+        use std::io::Read;
+
+        let compression = Compression::Gzip { min_bytes: 64 };
+        let (body, encoding) = compression.encode(Bytes::from_static(b"requests value=1i"));
+        assert_eq!(&b"requests value=1i"[..], &body[..]);
+        assert_eq!(None, encoding);
+
+        let lines = "requests value=1i\n".repeat(100);
+        let (body, encoding) = compression.encode(Bytes::from(lines.clone()));
+        assert_eq!(Some("gzip"), encoding);
+        assert!(body.len() < lines.len() / 10);
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(lines, decoded);
+    }
+
+    #[test]
+    fn test_v2_config_signin() {
+        let config = InfluxV2ConfigBuilder::default()
+            .endpoint("http://localhost:8086")
+            .bucket("metrics")
+            .org("org")
+            .username(Some("username".to_string()))
+            .password(Some("password".to_string()))
+            .build()
+            .unwrap();
+
+        let client = Client::new();
+        let request = config.request(&client).build().unwrap();
+        assert!(request.headers().get(AUTHORIZATION).is_none());
+
+        let signin = config.signin(&client).unwrap().build().unwrap();
+        assert_eq!("http://localhost:8086/api/v2/signin", signin.url().as_str());
+        assert_eq!(
+            "Basic dXNlcm5hbWU6cGFzc3dvcmQ=",
+            signin.headers()[AUTHORIZATION]
+        );
+    }
+
+    #[test]
+    fn test_v3_config() {
+        let expected_config = InfluxV3Config {
+            endpoint: "http://localhost:8181".to_owned(),
+            db: "metrics".to_owned(),
+            token: Some("token".to_owned()),
+            precision: Some(Precision::Milliseconds),
+            accept_partial: Some(false),
+            no_sync: Some(true),
+        };
+
+        let expected_params = vec![
+            ("db", "metrics".to_owned()),
+            ("precision", "millisecond".to_owned()),
+            ("accept_partial", "false".to_owned()),
+            ("no_sync", "true".to_owned()),
+        ];
+
+        let config = InfluxV3ConfigBuilder::default()
+            .endpoint("http://localhost:8181")
+            .db("metrics")
+            .token(Some("token".to_string()))
+            .precision(Some(Precision::Milliseconds))
+            .accept_partial(Some(false))
+            .no_sync(Some(true))
+            .build();
+
+        assert!(config.is_ok());
+        let config = config.unwrap();
+        assert_eq!(expected_config, config);
+        assert_eq!(expected_params, config.parameters());
+
+        let request = config.request(&Client::new()).build().unwrap();
+        assert_eq!(
+            "http://localhost:8181/api/v3/write_lp?db=metrics&precision=millisecond&accept_partial=false&no_sync=true",
+            request.url().as_str()
+        );
+        assert_eq!("Bearer token", request.headers()[AUTHORIZATION]);
+    }
+
+    #[test]
+    fn test_v3_config_unsupported_precision() {
+        let config = InfluxV3ConfigBuilder::default()
+            .endpoint("http://localhost:8181")
+            .db("metrics")
+            .precision(Some(Precision::Hours))
+            .build();
+
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_recorder_config_overrides() {
+        let config = RecorderConfigBuilder::default()
+            .buckets(Some(vec![1.0, 10.0]))
+            .set_buckets_for_metric(Matcher::Suffix("_seconds".to_owned()), &[0.1, 1.0])
+            .set_quantiles_for_metric(Matcher::Prefix("http_".to_owned()), &[0.5, 0.99])
+            .build();
+
+        assert!(config.is_ok());
+        let config = config.unwrap();
+        assert_eq!(Some(vec![1.0, 10.0]), config.buckets);
+        assert_eq!(
+            vec![(Matcher::Suffix("_seconds".to_owned()), vec![0.1, 1.0])],
+            config.bucket_overrides
+        );
+        assert_eq!(
+            vec![(Matcher::Prefix("http_".to_owned()), vec![0.5, 0.99])],
+            config.quantile_overrides
+        );
+
+        let config = RecorderConfigBuilder::default()
+            .set_buckets_for_metric(Matcher::Full("latency".to_owned()), &[])
+            .build();
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_recorder_config_non_finite_replacement() {
+        let config = RecorderConfigBuilder::default()
+            .non_finite_floats(NonFiniteFloats::Replace(-1.0))
+            .build();
+        assert!(config.is_ok());
+
+        let config = RecorderConfigBuilder::default()
+            .non_finite_floats(NonFiniteFloats::Replace(f64::NAN))
+            .build();
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_recorder_config_name_tag_collision() {
+        let config = RecorderConfigBuilder::default()
+            .measurement_naming(MeasurementNaming::Single {
+                measurement: "app".to_owned(),
+                tag: "name".to_owned(),
+            })
+            .global_tag("name", "api")
+            .build();
+
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_recorder_config_global_tags() {
+        let config = RecorderConfigBuilder::default()
+            .global_tag("service", "api")
+            .global_tag("env", "staging")
+            .global_tag("env", "production")
+            .global_tag_from_env("path", "PATH")
+            .global_tag_from_env("zone", "METRICS_INFLUXDB_TEST_UNSET")
+            .global_tag_from_hostname("host")
+            .build()
+            .unwrap();
+
+        let tags = config
+            .global_tags
+            .iter()
+            .map(|(tag, value)| (tag.as_str(), value.as_str()))
+            .collect::<Vec<(&str, &str)>>();
+        assert_eq!(vec![("service", "api"), ("env", "production")], tags[..2]);
+        let value = |name: &str| {
+            tags.iter()
+                .find(|(tag, _)| *tag == name)
+                .map(|(_, value)| value.to_string())
+        };
+        assert_eq!(
+            std::env::var("PATH").ok().filter(|path| !path.is_empty()),
+            value("path")
+        );
+        assert_eq!(None, value("zone"));
+        // Not every machine has a hostname, but it is never written empty.
+        assert!(value("host").is_none_or(|host| !host.is_empty()));
+    }
 }
@@ -6,6 +6,8 @@ use std::time::Duration;
 use metrics_util::{Histogram, Quantile, Summary};
 use quanta::Instant;
 
+use crate::config::Matcher;
+
 /// Distribution type.
 #[derive(Clone)]
 pub enum Distribution {
@@ -57,33 +59,52 @@ impl Distribution {
 pub struct DistributionBuilder {
     quantiles: Arc<Vec<Quantile>>,
     buckets: Option<Vec<f64>>,
+    bucket_overrides: Vec<(Matcher, Vec<f64>)>,
+    quantile_overrides: Vec<(Matcher, Arc<Vec<Quantile>>)>,
 }
 
 impl DistributionBuilder {
     /// Creates a new instance of `DistributionBuilder`.
-    pub fn new(quantiles: Vec<Quantile>, buckets: Option<Vec<f64>>) -> DistributionBuilder {
+    pub fn new(
+        quantiles: Vec<Quantile>,
+        buckets: Option<Vec<f64>>,
+        bucket_overrides: Vec<(Matcher, Vec<f64>)>,
+        quantile_overrides: Vec<(Matcher, Vec<Quantile>)>,
+    ) -> DistributionBuilder {
         DistributionBuilder {
             quantiles: Arc::new(quantiles),
             buckets,
+            bucket_overrides,
+            quantile_overrides: quantile_overrides
+                .into_iter()
+                .map(|(matcher, quantiles)| (matcher, Arc::new(quantiles)))
+                .collect(),
         }
     }
 
     /// Returns a distribution for the given metric key.
-    pub fn get_distribution(&self) -> Distribution {
+    ///
+    /// Per-metric buckets take precedence over per-metric quantiles, which take precedence over
+    /// the global buckets and quantiles.
+    pub fn get_distribution(&self, name: &str) -> Distribution {
+        for (matcher, buckets) in &self.bucket_overrides {
+            if matcher.matches(name) {
+                return Distribution::new_histogram(buckets);
+            }
+        }
+
+        for (matcher, quantiles) in &self.quantile_overrides {
+            if matcher.matches(name) {
+                return Distribution::new_summary(quantiles.clone());
+            }
+        }
+
         if let Some(ref buckets) = self.buckets {
             return Distribution::new_histogram(buckets);
         }
 
         Distribution::new_summary(self.quantiles.clone())
     }
-
-    /// Returns the distribution type for the given metric key.
-    pub fn get_distribution_type(&self) -> &str {
-        if self.buckets.is_some() {
-            return "histogram";
-        }
-        "summary"
-    }
 }
 
 #[derive(Clone)]
@@ -200,7 +221,8 @@ impl RollingSummary {
 
             self.buckets.truncate(self.max_buckets - 1);
             self.buckets.push(Bucket { begin, summary });
-            self.buckets.sort_unstable_by(|a, b| b.begin.cmp(&a.begin));
+            self.buckets
+                .sort_unstable_by_key(|b| std::cmp::Reverse(b.begin));
         }
     }
 
@@ -241,9 +263,44 @@ impl RollingSummary {
     pub fn count(&self) -> usize {
         self.count
     }
+}
+
+#[cfg(test)]
+mod test {
+    use metrics_util::parse_quantiles;
+
+    use crate::distribution::*;
 
-    #[cfg(test)]
-    fn buckets(&self) -> &Vec<Bucket> {
-        &self.buckets
+    #[test]
+    fn test_get_distribution_overrides() {
+        let builder = DistributionBuilder::new(
+            parse_quantiles(&[0.5]),
+            None,
+            vec![
+                (Matcher::Suffix("_seconds".to_owned()), vec![0.1, 1.0]),
+                (Matcher::Full("payload_bytes".to_owned()), vec![1024.0]),
+            ],
+            vec![(
+                Matcher::Prefix("http_".to_owned()),
+                parse_quantiles(&[0.9, 0.99]),
+            )],
+        );
+
+        let is_histogram =
+            |name| matches!(builder.get_distribution(name), Distribution::Histogram(_));
+        assert!(is_histogram("request_seconds"));
+        // Buckets win over quantiles when both match.
+        assert!(is_histogram("http_request_seconds"));
+        assert!(is_histogram("payload_bytes"));
+        assert!(!is_histogram("payload_bytes_total"));
+
+        match builder.get_distribution("http_requests") {
+            Distribution::Summary(_, quantiles, _) => assert_eq!(2, quantiles.len()),
+            Distribution::Histogram(_) => panic!("expected a summary"),
+        }
+        match builder.get_distribution("requests") {
+            Distribution::Summary(_, quantiles, _) => assert_eq!(1, quantiles.len()),
+            Distribution::Histogram(_) => panic!("expected a summary"),
+        }
     }
 }
@@ -1,17 +1,187 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, InfluxError>;
 
+/// Callback invoked with every error hit while flushing metrics in the background.
+pub type ErrorHandler = Arc<dyn Fn(&InfluxError) + Send + Sync>;
+
 #[derive(Debug, Error)]
 pub enum InfluxError {
     #[error("{error}")]
-    AuthorizationError { error: String },
+    BadRequest { error: ApiError },
+    #[error("{error}")]
+    AuthenticationError { error: ApiError },
+    #[error("{error}")]
+    AuthorizationError { error: ApiError },
+    #[error("{error}")]
+    NotFound { error: ApiError },
+    #[error("{error}")]
+    ContentTooLarge { error: ApiError },
     #[error("{error}")]
-    AuthenticationError { error: String },
+    TooManyRequests {
+        error: ApiError,
+        retry_after: Option<Duration>,
+    },
     #[error("{error}")]
-    BadRequest { error: String },
+    ClientError { error: ApiError },
     #[error("{error}")]
-    ContentTooLarge { error: String },
+    ServerError {
+        error: ApiError,
+        retry_after: Option<Duration>,
+    },
+    #[error("Unexpected response: {error}")]
+    UnexpectedStatus { error: ApiError },
     #[error("Connection error: {0}")]
     ConnectionError(#[from] reqwest::Error),
 }
+
+impl InfluxError {
+    /// Maps an unsuccessful response to the variant matching its status code.
+    pub(crate) fn from_response(error: ApiError, retry_after: Option<Duration>) -> Self {
+        match error.status {
+            400 => InfluxError::BadRequest { error },
+            401 => InfluxError::AuthenticationError { error },
+            403 => InfluxError::AuthorizationError { error },
+            404 => InfluxError::NotFound { error },
+            413 => InfluxError::ContentTooLarge { error },
+            429 => InfluxError::TooManyRequests { error, retry_after },
+            status if (400..500).contains(&status) => InfluxError::ClientError { error },
+            status if (500..600).contains(&status) => {
+                InfluxError::ServerError { error, retry_after }
+            }
+            _ => InfluxError::UnexpectedStatus { error },
+        }
+    }
+
+    /// The error response returned by the server, unless the server could not be reached.
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            InfluxError::BadRequest { error }
+            | InfluxError::AuthenticationError { error }
+            | InfluxError::AuthorizationError { error }
+            | InfluxError::NotFound { error }
+            | InfluxError::ContentTooLarge { error }
+            | InfluxError::TooManyRequests { error, .. }
+            | InfluxError::ClientError { error }
+            | InfluxError::ServerError { error, .. }
+            | InfluxError::UnexpectedStatus { error } => Some(error),
+            InfluxError::ConnectionError(_) => None,
+        }
+    }
+
+    /// The delay the server asked for through the `Retry-After` header, if any.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            InfluxError::TooManyRequests { retry_after, .. }
+            | InfluxError::ServerError { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// An error found while parsing line protocol.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("line {line}, column {column}: {message}")]
+pub struct ParseError {
+    /// The line of the error, starting at 1.
+    pub line: usize,
+    /// The column of the error in characters, starting at 1.
+    pub column: usize,
+    pub message: String,
+}
+
+/// An error response returned by InfluxDB.
+///
+/// The `code`, `message`, `line` and `op` fields are filled from the JSON body when the server
+/// sent one, `body` always holds the raw response body.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    pub status: u16,
+    pub code: Option<String>,
+    pub message: Option<String>,
+    pub line: Option<i32>,
+    pub op: Option<String>,
+    pub body: String,
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP {}", self.status)?;
+        if let Some(ref code) = self.code {
+            write!(f, " ({code})")?;
+        }
+        match self.message {
+            Some(ref message) => write!(f, ": {message}")?,
+            None if !self.body.is_empty() => write!(f, ": {}", self.body)?,
+            None => {}
+        }
+        if let Some(line) = self.line {
+            write!(f, " at line {line}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::*;
+
+    #[test]
+    fn test_from_response() {
+        let error = |status| ApiError {
+            status,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            InfluxError::from_response(error(400), None),
+            InfluxError::BadRequest { .. }
+        ));
+        assert!(matches!(
+            InfluxError::from_response(error(404), None),
+            InfluxError::NotFound { .. }
+        ));
+        assert!(matches!(
+            InfluxError::from_response(error(422), None),
+            InfluxError::ClientError { .. }
+        ));
+        assert!(matches!(
+            InfluxError::from_response(error(503), Some(Duration::from_secs(1))),
+            InfluxError::ServerError {
+                retry_after: Some(_),
+                ..
+            }
+        ));
+        assert!(matches!(
+            InfluxError::from_response(error(302), None),
+            InfluxError::UnexpectedStatus { .. }
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        let error = ApiError {
+            status: 400,
+            code: Some("invalid".to_owned()),
+            message: Some("unable to parse".to_owned()),
+            line: Some(2),
+            op: None,
+            body: String::new(),
+        };
+        assert_eq!(
+            "HTTP 400 (invalid): unable to parse at line 2",
+            error.to_string()
+        );
+
+        let error = ApiError {
+            status: 502,
+            body: "Bad Gateway".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!("HTTP 502: Bad Gateway", error.to_string());
+    }
+}
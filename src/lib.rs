@@ -1,7 +1,16 @@
+mod batch;
+#[doc(hidden)]
+pub mod bench;
+mod cardinality;
 pub mod client;
 pub mod config;
 mod distribution;
 pub mod error;
 pub mod metric;
+pub mod parser;
 mod registry;
+mod report;
+pub mod retry;
+mod serializer;
+mod stats;
 mod types;
@@ -1,28 +1,26 @@
-use std::fmt::{Display, Formatter};
-use std::slice::Iter;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-
-use itertools::Itertools;
-use metrics::{Key, Label};
-use once_cell::sync::Lazy;
-use regex::Regex;
-use tokio::io::AsyncReadExt;
-
-use crate::distribution::Distribution;
-use crate::types::Type;
+use std::fmt::{Display, Formatter, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use derive_builder::Builder;
+use metrics::Unit;
+use metrics_util::MetricKind;
 
-const RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"([,="])"#).unwrap());
+use crate::config::{NonFiniteFloats, Precision};
+use crate::types::Type;
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct Metric {
-    measurement: String,
-    fields: Vec<(String, Type)>,
-    tags: Vec<(String, Type)>,
-    timestamp: Option<u128>,
+    pub(crate) measurement: String,
+    pub(crate) fields: Vec<(String, Type)>,
+    pub(crate) tags: Vec<(String, Type)>,
+    pub(crate) timestamp: Option<i128>,
 }
 
 impl Metric {
+    /// Creates a metric written to `measurement`.
+    ///
+    /// A line starting with `#` is a comment, so a measurement starting with `#` is written as
+    /// `\#`, which InfluxDB stores along with the backslash, e.g. `\#cpu` for `#cpu`.
     pub fn new(measurement: impl Into<String>) -> Self {
         Metric {
             measurement: measurement.into(),
@@ -41,138 +39,522 @@ impl Metric {
         self.tags.push((tag.into(), value.into()));
         self
     }
+
+    /// Sets the time of the point, which the server defaults to the time it receives it at.
+    pub fn timestamp(mut self, timestamp: impl Into<Timestamp>) -> Self {
+        self.timestamp = Some(timestamp.into().nanoseconds());
+        self
+    }
+
+    /// Formats the metric as a line according to `format`.
+    ///
+    /// The line is empty when the metric has no field, or the [`NonFiniteFloats`] policy of
+    /// `format` drops it.
+    ///
+    /// The [`Display`] implementation uses the default [`LineFormat`].
+    pub fn to_line(&self, format: &LineFormat) -> String {
+        let mut line = String::new();
+        self.write_line(&mut line, format)
+            .expect("writing to a String cannot fail");
+        line
+    }
+}
+
+/// Options for how metrics are written as line protocol.
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd, Builder)]
+#[builder(setter(into))]
+#[builder(default)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct LineFormat {
+    /// The precision timestamps are written in.
+    pub(crate) precision: Precision,
+    /// Whether unsigned integers are written with the `u` suffix, which InfluxDB supports since
+    /// 1.8. Otherwise they are written as signed integers, saturating at `i64::MAX`.
+    pub(crate) unsigned_integers: bool,
+    /// See [`NonFiniteFloats`].
+    pub(crate) non_finite_floats: NonFiniteFloats,
+}
+
+impl LineFormatBuilder {
+    fn validate(&self) -> Result<(), String> {
+        match self.non_finite_floats {
+            Some(ref non_finite_floats) => non_finite_floats.validate(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What a metric was described with, see [`InfluxRecorder::metadata`].
+///
+/// [`InfluxRecorder::metadata`]: crate::client::InfluxRecorder::metadata
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub name: String,
+    pub kind: MetricKind,
+    pub unit: Option<Unit>,
+    pub description: String,
+}
+
+/// A point in time, stored as nanoseconds since the Unix epoch.
+///
+/// Times before the epoch are negative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(i128);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    pub fn nanoseconds(&self) -> i128 {
+        self.0
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(value: SystemTime) -> Self {
+        match value.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.into(),
+            Err(err) => Timestamp(-Timestamp::from(err.duration()).0),
+        }
+    }
+}
+
+/// A duration since the Unix epoch.
+impl From<Duration> for Timestamp {
+    fn from(value: Duration) -> Self {
+        Timestamp(i128::try_from(value.as_nanos()).unwrap_or(i128::MAX))
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl<Tz: chrono::TimeZone> From<chrono::DateTime<Tz>> for Timestamp {
+    fn from(value: chrono::DateTime<Tz>) -> Self {
+        Timestamp(
+            value.timestamp() as i128 * 1_000_000_000 + value.timestamp_subsec_nanos() as i128,
+        )
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<time::OffsetDateTime> for Timestamp {
+    fn from(value: time::OffsetDateTime) -> Self {
+        Timestamp(value.unix_timestamp_nanos())
+    }
 }
 
 impl Display for Metric {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let measurement = if self.tags.is_empty() {
-            escape_string(self.measurement.clone())
-        } else {
-            escape_string(self.measurement.clone()) + ","
+        self.write_line(f, &LineFormat::default()).map(|_| ())
+    }
+}
+
+impl Metric {
+    /// Writes the metric as a line according to `format`, returning how many non-finite float
+    /// values were left out.
+    ///
+    /// Nothing is written when the metric has no field, or the [`NonFiniteFloats`] policy drops
+    /// the whole line or leaves it without any field.
+    pub(crate) fn write_line(
+        &self,
+        f: &mut impl Write,
+        format: &LineFormat,
+    ) -> Result<usize, std::fmt::Error> {
+        let non_finite = self
+            .fields
+            .iter()
+            .filter(|(_, value)| matches!(value, Type::Float(float) if !float.is_finite()))
+            .count();
+        let drop_line = match format.non_finite_floats {
+            NonFiniteFloats::DropLine => non_finite > 0,
+            NonFiniteFloats::DropField => non_finite == self.fields.len(),
+            NonFiniteFloats::Clamp | NonFiniteFloats::Replace(_) => false,
         };
+        if drop_line || self.fields.is_empty() {
+            return Ok(non_finite);
+        }
 
         let tags = self
             .tags
             .iter()
-            .map(|(tag, value)| {
-                format!(
-                    "{}={}",
-                    escape_string(tag),
-                    escape_string(value.to_string())
-                )
-            })
-            .join(",");
-
-        let fields = self
-            .fields
-            .iter()
-            .map(|(field, value)| {
-                format!(
-                    "{}={}",
-                    escape_string(field),
-                    escape_string(value.to_string())
-                )
-            })
-            .join(",");
+            .map(|(tag, value)| (tag, value.to_string()))
+            .collect::<Vec<(&String, String)>>();
+        write_prefix(
+            f,
+            &self.measurement,
+            tags.iter()
+                .map(|(tag, value)| (tag.as_str(), value.as_str())),
+        )?;
 
-        let ilp = vec![tags, fields].join(" ");
-        write!(f, "{measurement}{ilp}")
-    }
-}
+        let mut dropped = 0;
+        let mut separator = ' ';
+        for (field, value) in &self.fields {
+            let replacement = match value {
+                Type::Float(float) if !float.is_finite() => {
+                    match format.non_finite_floats.replace(*float) {
+                        Some(replacement) => Some(Type::Float(replacement)),
+                        None => {
+                            dropped += 1;
+                            continue;
+                        }
+                    }
+                }
+                _ => None,
+            };
+
+            f.write_char(separator)?;
+            separator = ',';
+            write_escaped(f, field, KEY_SPECIAL)?;
+            f.write_char('=')?;
+            write_value(f, replacement.as_ref().unwrap_or(value), format)?;
+        }
 
-fn escape_string(value: impl Into<String>) -> String {
-    let value = value.into();
-    let value = RE.replace_all(value.as_str(), "\\${1}").to_string();
-    if value.contains(" ") {
-        format!("\"{value}\"")
-    } else {
-        value.clone()
+        if let Some(timestamp) = self.timestamp {
+            write!(f, " {}", format.precision.convert_nanoseconds(timestamp))?;
+        }
+        Ok(dropped)
     }
 }
 
-impl From<(&Key, &Arc<AtomicU64>)> for Metric {
-    fn from(value: (&Key, &Arc<AtomicU64>)) -> Self {
-        let (key, value) = value;
-        let tags = parse_labels(key.labels());
-        Metric {
-            measurement: key.name().to_string(),
-            fields: vec![("value".to_owned(), value.load(Ordering::Relaxed).into())],
-            tags,
-            timestamp: None,
+/// Characters escaped in measurements.
+const MEASUREMENT_SPECIAL: &[char] = &[',', ' '];
+/// Characters escaped in tag keys, tag values and field keys.
+pub(crate) const KEY_SPECIAL: &[char] = &[',', '=', ' '];
+
+/// Writes `value` with a backslash in front of every `special` character.
+///
+/// Line protocol has no way to represent newlines outside of string fields, so they are written
+/// as a literal `\n` (and `\r`). A backslash is only escaped when it comes right before a special
+/// character, another backslash or the end of the value, where it would otherwise change the
+/// meaning of what follows.
+pub(crate) fn write_escaped(f: &mut impl Write, value: &str, special: &[char]) -> std::fmt::Result {
+    let mut chars = value.chars().peekable();
+    while let Some(char) = chars.next() {
+        match char {
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\\' if chars
+                .peek()
+                .is_none_or(|next| *next == '\\' || special.contains(next)) =>
+            {
+                f.write_str("\\\\")?
+            }
+            char if special.contains(&char) => {
+                f.write_char('\\')?;
+                f.write_char(char)?;
+            }
+            char => f.write_char(char)?,
         }
     }
+    Ok(())
 }
 
-impl From<(&Key, Distribution)> for Metric {
-    fn from((key, value): (&Key, Distribution)) -> Self {
-        let tags = parse_labels(key.labels());
-
-        let fields = match value {
-            Distribution::Histogram(histogram) => vec![
-                ("sum".to_owned(), histogram.sum().into()),
-                ("count".to_owned(), histogram.count().into()),
-            ]
-            .into_iter()
-            .chain(
-                histogram
-                    .buckets()
-                    .into_iter()
-                    .map(|(label, count)| (format!("{:.2}", label), count.into())),
-            )
-            .collect(),
-            Distribution::Summary(summary, quantiles, sum) => vec![
-                ("sum".to_owned(), sum.into()),
-                ("count".to_owned(), summary.count().into()),
-            ]
-            .into_iter()
-            .chain(
-                quantiles
-                    .iter()
-                    .map(|quantile| (quantile.label().to_owned(), quantile.value().into())),
-            )
-            .collect(),
-        };
+/// Writes a measurement name, escaped like [`write_escaped`], with a leading `#` written as `\#`
+/// (see [`Metric::new`]).
+pub(crate) fn write_measurement(f: &mut impl Write, measurement: &str) -> std::fmt::Result {
+    if measurement.starts_with('#') {
+        f.write_char('\\')?;
+    }
+    write_escaped(f, measurement, MEASUREMENT_SPECIAL)
+}
 
-        Metric {
-            measurement: key.name().to_string(),
-            fields,
-            tags,
-            timestamp: None,
+/// Writes the escaped `measurement` and `tags`.
+///
+/// Tags without a name or a value are skipped, as well as the ones whose name comes up again,
+/// the first one wins.
+pub(crate) fn write_prefix<'a>(
+    f: &mut impl Write,
+    measurement: &str,
+    tags: impl Iterator<Item = (&'a str, &'a str)>,
+) -> std::fmt::Result {
+    write_measurement(f, measurement)?;
+    let mut written = vec![];
+    for (tag, value) in tags {
+        // Tags without a value are invalid, InfluxDB treats them as absent anyway.
+        if tag.is_empty() || value.is_empty() || written.contains(&tag) {
+            continue;
         }
+        written.push(tag);
+        f.write_char(',')?;
+        write_escaped(f, tag, KEY_SPECIAL)?;
+        f.write_char('=')?;
+        write_escaped(f, value, KEY_SPECIAL)?;
     }
+    Ok(())
 }
 
-fn parse_labels(labels: Iter<Label>) -> Vec<(String, Type)> {
-    labels
-        .map(|label| {
-            let (key, value) = label.clone().into_parts();
-            (key.to_string(), value.to_string().into())
-        })
-        .collect()
+/// Writes a field value, strings are quoted and unsigned integers suffixed according to `format`.
+pub(crate) fn write_value(
+    f: &mut impl Write,
+    value: &Type,
+    format: &LineFormat,
+) -> std::fmt::Result {
+    match value {
+        Type::Text(text) => write_string_field(f, text),
+        Type::UnsignedInteger(value) if format.unsigned_integers => write!(f, "{value}u"),
+        value => write!(f, "{value}"),
+    }
 }
 
+/// Writes `value` as a double quoted string field, escaping double quotes and backslashes.
+///
+/// Newlines are written as a literal `\n` (and `\r`) like everywhere else in the line.
+pub(crate) fn write_string_field(f: &mut impl Write, value: &str) -> std::fmt::Result {
+    f.write_char('"')?;
+    for char in value.chars() {
+        match char {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            char => f.write_char(char)?,
+        }
+    }
+    f.write_char('"')
+}
+
+#[cfg(test)]
 mod test {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use crate::parser::parse;
+
     use crate::metric::*;
 
     #[test]
-    fn test_escape_string() {
+    fn test_escape() {
+        let metric = Metric::new("my measurement,=")
+            .tag("tag key,=", "tag value,=")
+            .field("field key,=", "field \"value\" \\ ,=");
+
         assert_eq!(
-            "\"test\\=Hello\\, world!\"",
-            escape_string("test=Hello, world!")
-        )
+            r#"my\ measurement\,=,tag\ key\,\==tag\ value\,\= field\ key\,\=="field \"value\" \\ ,=""#,
+            metric.to_string()
+        );
+    }
+
+    #[test]
+    fn test_escape_backslashes() {
+        let metric = Metric::new("a\\").tag("b\\c", "d\\,").field("e", "f\\");
+
+        assert_eq!(r#"a\\,b\c=d\\\, e="f\\""#, metric.to_string());
+    }
+
+    #[test]
+    fn test_escape_newlines() {
+        let metric = Metric::new("a\nb").tag("c", "d\r\n").field("e", "f\ng");
+
+        assert_eq!(r#"a\nb,c=d\r\n e="f\ng""#, metric.to_string());
+    }
+
+    #[test]
+    fn test_escape_comment() {
+        let metric = Metric::new("#cpu").tag("#core", "#0").field("#usage", 1.5);
+
+        let line = metric.to_string();
+        assert_eq!(r#"\#cpu,#core=#0 #usage=1.5"#, line);
+        // InfluxDB keeps the backslash, so does the parser.
+        assert_eq!(
+            vec![Metric::new("\\#cpu")
+                .tag("#core", "#0")
+                .field("#usage", 1.5)],
+            parse(&line).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unsigned_integers() {
+        let metric = Metric::new("test")
+            .field("small", 10u64)
+            .field("large", u64::MAX);
+
+        assert_eq!(
+            "test small=10i,large=9223372036854775807i",
+            metric.to_string()
+        );
+
+        let format = LineFormatBuilder::default()
+            .unsigned_integers(true)
+            .build()
+            .unwrap();
+        let line = metric.to_line(&format);
+        assert_eq!("test small=10u,large=18446744073709551615u", line);
+        assert_eq!(vec![metric], parse(&line).unwrap());
+    }
+
+    #[test]
+    fn test_non_finite_floats() {
+        let metric = Metric::new("test")
+            .field("nan", f64::NAN)
+            .field("finite", 1.5)
+            .field("infinite", f64::NEG_INFINITY);
+        let line = |policy: NonFiniteFloats| {
+            let format = LineFormatBuilder::default()
+                .non_finite_floats(policy)
+                .build()
+                .unwrap();
+            let mut line = String::new();
+            let dropped = metric.write_line(&mut line, &format).unwrap();
+            (line, dropped)
+        };
+
+        assert_eq!(
+            ("test finite=1.5".to_owned(), 2),
+            line(NonFiniteFloats::DropField)
+        );
+        assert_eq!((String::new(), 2), line(NonFiniteFloats::DropLine));
+        assert_eq!(
+            (format!("test nan=0,finite=1.5,infinite={}", f64::MIN), 0),
+            line(NonFiniteFloats::Clamp)
+        );
+        assert_eq!(
+            ("test nan=-1,finite=1.5,infinite=-1".to_owned(), 0),
+            line(NonFiniteFloats::Replace(-1.0))
+        );
+
+        let metric = Metric::new("test").field("nan", f64::NAN);
+        assert_eq!("", metric.to_string());
+        let metric = Metric::new("test").tag("host", "a");
+        assert_eq!("", metric.to_line(&precision(Precision::Seconds)));
+
+        for replacement in [f64::NAN, f64::INFINITY] {
+            assert!(LineFormatBuilder::default()
+                .non_finite_floats(NonFiniteFloats::Replace(replacement))
+                .build()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_skip_empty_tags() {
+        let metric = Metric::new("test").tag("empty", "").field("value", 1);
+
+        assert_eq!("test value=1i", metric.to_string());
+    }
+
+    #[test]
+    fn test_duplicate_tags() {
+        let metric = Metric::new("test")
+            .tag("host", "a")
+            .tag("host", "b")
+            .field("value", 1);
+
+        assert_eq!("test,host=a value=1i", metric.to_string());
     }
 
     #[test]
     fn test_display() {
-        let expected = r#"test,string=test escaped_string="te\ st",float_value=10.1,unsigned_value=10i,signed_value=10i"#.to_owned();
+        let expected = r#"test,string=test escaped_string="te\\ st",float_value=10.1,unsigned_value=10i,signed_value=10i,bool_value=true"#.to_owned();
 
         let metric = Metric::new("test")
             .tag("string", "test")
             .field("escaped_string", "te\\ st")
             .field("float_value", 10.1)
             .field("unsigned_value", 10u8)
-            .field("signed_value", 10i8);
+            .field("signed_value", 10i8)
+            .field("bool_value", true);
 
         assert_eq!(expected, metric.to_string());
     }
+
+    proptest! {
+        #[test]
+        fn test_display_round_trip(
+            measurement in "#?[^\r\n]+",
+            tags in vec(("[^\r\n]+", "[^\r\n]+"), 0..4),
+            fields in vec(("[^\r\n]+", field_value()), 1..4),
+        ) {
+            let metric = tags
+                .into_iter()
+                .fold(Metric::new(&measurement), |metric, (tag, value)| metric.tag(tag, value));
+            let metric = fields
+                .into_iter()
+                .fold(metric, |metric, (field, value)| metric.field(field, value));
+            let mut parsed = metric.clone();
+            if measurement.starts_with('#') {
+                parsed.measurement = format!("\\{measurement}");
+            }
+
+            let line = metric.to_string();
+            prop_assert!(!line.contains('\n'));
+            prop_assert_eq!(vec![parsed], parse(&line).unwrap());
+        }
+    }
+
+    fn field_value() -> impl Strategy<Value = Type> {
+        prop_oneof![
+            any::<bool>().prop_map(Type::Boolean),
+            any::<f64>()
+                .prop_filter("finite", |float| float.is_finite())
+                .prop_map(Type::Float),
+            any::<i64>().prop_map(Type::SignedInteger),
+            "[^\r\n]*".prop_map(Type::Text),
+        ]
+    }
+
+    fn precision(precision: Precision) -> LineFormat {
+        LineFormatBuilder::default()
+            .precision(precision)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let metric = Metric::new("test")
+            .field("value", 1)
+            .timestamp(Duration::new(1_700_000_000, 123_456_789));
+
+        assert_eq!("test value=1i 1700000000123456789", metric.to_string());
+        assert_eq!(
+            "test value=1i 1700000000123456",
+            metric.to_line(&precision(Precision::Microseconds))
+        );
+        assert_eq!(
+            "test value=1i 1700000000123",
+            metric.to_line(&precision(Precision::Milliseconds))
+        );
+        assert_eq!(
+            "test value=1i 1700000000",
+            metric.to_line(&precision(Precision::Seconds))
+        );
+        assert_eq!(
+            "test value=1i 28333333",
+            metric.to_line(&precision(Precision::Minutes))
+        );
+        assert_eq!(
+            "test value=1i 472222",
+            metric.to_line(&precision(Precision::Hours))
+        );
+
+        let metric = metric.timestamp(UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!("test value=1i 1000000000", metric.to_string());
+
+        let metric = metric.timestamp(UNIX_EPOCH - Duration::from_millis(1500));
+        assert_eq!("test value=1i -1500000000", metric.to_string());
+        assert_eq!(
+            "test value=1i -2",
+            metric.to_line(&precision(Precision::Seconds))
+        );
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_timestamp_chrono() {
+        let timestamp = chrono::DateTime::from_timestamp(1_700_000_000, 5).unwrap();
+        let metric = Metric::new("test").field("value", 1).timestamp(timestamp);
+
+        assert_eq!("test value=1i 1700000000000000005", metric.to_string());
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn test_timestamp_time() {
+        let timestamp =
+            time::OffsetDateTime::from_unix_timestamp_nanos(1_700_000_000_000_000_005).unwrap();
+        let metric = Metric::new("test").field("value", 1).timestamp(timestamp);
+
+        assert_eq!("test value=1i 1700000000000000005", metric.to_string());
+    }
 }
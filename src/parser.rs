@@ -0,0 +1,297 @@
+use crate::error::ParseError;
+use crate::metric::Metric;
+use crate::types::Type;
+
+/// Characters that can be escaped in measurements.
+const MEASUREMENT_ESCAPES: &[char] = &[',', ' ', '\\'];
+/// Characters that can be escaped in tag keys, tag values and field keys.
+const KEY_ESCAPES: &[char] = &[',', '=', ' ', '\\'];
+/// Characters that can be escaped in string field values.
+const STRING_ESCAPES: &[char] = &['"', '\\'];
+
+/// Parses line protocol into metrics, one per line.
+///
+/// Blank lines and comments, lines starting with `#`, are skipped. Integers keep their `i` or
+/// `u` suffixed type, numbers without a suffix are floats. Timestamps are read as nanoseconds.
+pub fn parse(input: &str) -> Result<Vec<Metric>, ParseError> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| LineParser::new(index + 1, line).parse())
+        .collect()
+}
+
+struct LineParser<'a> {
+    number: usize,
+    line: &'a str,
+    position: usize,
+}
+
+impl<'a> LineParser<'a> {
+    fn new(number: usize, line: &'a str) -> Self {
+        LineParser {
+            number,
+            line,
+            position: 0,
+        }
+    }
+
+    fn parse(mut self) -> Result<Metric, ParseError> {
+        let measurement = self.identifier(&[',', ' '], MEASUREMENT_ESCAPES, "measurement")?;
+        let mut metric = Metric::new(measurement);
+
+        while self.consume(',') {
+            let tag = self.identifier(&['=', ',', ' '], KEY_ESCAPES, "tag key")?;
+            self.expect('=')?;
+            let value = self.identifier(&[',', ' '], KEY_ESCAPES, "tag value")?;
+            metric = metric.tag(tag, value);
+        }
+
+        self.expect(' ')?;
+        loop {
+            let field = self.identifier(&['=', ',', ' '], KEY_ESCAPES, "field key")?;
+            self.expect('=')?;
+            let value = self.field_value()?;
+            metric = metric.field(field, value);
+            if !self.consume(',') {
+                break;
+            }
+        }
+
+        if self.consume(' ') {
+            metric.timestamp = Some(self.timestamp()?);
+        }
+
+        match self.peek() {
+            None => Ok(metric),
+            Some(char) => Err(self.error(format!("unexpected character {char:?}"))),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.line[self.position..].chars().next()
+    }
+
+    fn consume(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.position += expected.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(char) if char == expected => {
+                self.position += char.len_utf8();
+                Ok(())
+            }
+            Some(char) => Err(self.error(format!("expected {expected:?}, found {char:?}"))),
+            None => Err(self.error(format!("expected {expected:?}, found end of line"))),
+        }
+    }
+
+    /// Reads an unquoted element up to the first unescaped `delimiters` character.
+    ///
+    /// A backslash only escapes the characters in `escapes`, in front of anything else it is kept
+    /// as is.
+    fn identifier(
+        &mut self,
+        delimiters: &[char],
+        escapes: &[char],
+        name: &str,
+    ) -> Result<String, ParseError> {
+        let mut value = String::new();
+        let mut chars = self.line[self.position..].chars().peekable();
+        while let Some(&char) = chars.peek() {
+            if delimiters.contains(&char) {
+                break;
+            }
+            chars.next();
+            self.position += char.len_utf8();
+            match (char, chars.peek()) {
+                ('\\', Some(&next)) if escapes.contains(&next) => {
+                    chars.next();
+                    self.position += next.len_utf8();
+                    value.push(next);
+                }
+                (char, _) => value.push(char),
+            }
+        }
+
+        if value.is_empty() {
+            return Err(self.error(format!("missing {name}")));
+        }
+        Ok(value)
+    }
+
+    fn field_value(&mut self) -> Result<Type, ParseError> {
+        if self.consume('"') {
+            return self.string_field().map(Type::Text);
+        }
+
+        let start = self.position;
+        let end = self.line[start..]
+            .find([',', ' '])
+            .map_or(self.line.len(), |end| start + end);
+        let value = &self.line[start..end];
+        let value = match value {
+            "" => return Err(self.error("missing field value".to_owned())),
+            "t" | "T" | "true" | "True" | "TRUE" => Type::Boolean(true),
+            "f" | "F" | "false" | "False" | "FALSE" => Type::Boolean(false),
+            value => {
+                if let Some(integer) = value.strip_suffix('i') {
+                    integer.parse().map(Type::SignedInteger).map_err(|err| {
+                        self.error(format!("invalid integer field value {value:?}: {err}"))
+                    })?
+                } else if let Some(unsigned) = value.strip_suffix('u') {
+                    unsigned.parse().map(Type::UnsignedInteger).map_err(|err| {
+                        self.error(format!("invalid unsigned field value {value:?}: {err}"))
+                    })?
+                } else {
+                    match value.parse::<f64>() {
+                        Ok(float) if float.is_finite() => Type::Float(float),
+                        _ => return Err(self.error(format!("invalid field value {value:?}"))),
+                    }
+                }
+            }
+        };
+        self.position = end;
+        Ok(value)
+    }
+
+    /// Reads a string field value, the opening quote is already consumed.
+    fn string_field(&mut self) -> Result<String, ParseError> {
+        let mut value = String::new();
+        let mut chars = self.line[self.position..].chars().peekable();
+        while let Some(char) = chars.next() {
+            self.position += char.len_utf8();
+            match (char, chars.peek()) {
+                ('"', _) => return Ok(value),
+                ('\\', Some(&next)) if STRING_ESCAPES.contains(&next) => {
+                    chars.next();
+                    self.position += next.len_utf8();
+                    value.push(next);
+                }
+                (char, _) => value.push(char),
+            }
+        }
+        Err(self.error("unterminated string field value".to_owned()))
+    }
+
+    /// Reads a timestamp, a signed 64-bit number of nanoseconds.
+    fn timestamp(&mut self) -> Result<i128, ParseError> {
+        let start = self.position;
+        let end = self.line[start..]
+            .find(' ')
+            .map_or(self.line.len(), |end| start + end);
+        let value = &self.line[start..end];
+        let timestamp = value
+            .parse::<i64>()
+            .map_err(|err| self.error(format!("invalid timestamp {value:?}: {err}")))?;
+        self.position = end;
+        Ok(timestamp.into())
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            line: self.number,
+            column: self.line[..self.position].chars().count() + 1,
+            message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parser::*;
+
+    #[test]
+    fn test_parse() {
+        let input = "# comment\n\
+            cpu,host=a\\ b,region=eu usage=0.5,cores=8i,total=18446744073709551615u,up=T,name=\"x \\\"y\\\" \\\\z\" 1700000000000000000\n\
+            \n\
+            my\\ measurement\\,x value=t";
+
+        let mut expected = vec![
+            Metric::new("cpu")
+                .tag("host", "a b")
+                .tag("region", "eu")
+                .field("usage", 0.5)
+                .field("cores", 8i64)
+                .field("total", u64::MAX)
+                .field("up", true)
+                .field("name", "x \"y\" \\z"),
+            Metric::new("my measurement,x").field("value", true),
+        ];
+        expected[0].timestamp = Some(1_700_000_000_000_000_000);
+
+        assert_eq!(expected, parse(input).unwrap());
+    }
+
+    #[test]
+    fn test_parse_booleans() {
+        for (value, expected) in [
+            ("t", true),
+            ("T", true),
+            ("true", true),
+            ("True", true),
+            ("TRUE", true),
+            ("f", false),
+            ("F", false),
+            ("false", false),
+            ("False", false),
+            ("FALSE", false),
+        ] {
+            let metrics = parse(&format!("test value={value}")).unwrap();
+            assert_eq!(vec![Metric::new("test").field("value", expected)], metrics);
+        }
+    }
+
+    #[test]
+    fn test_parse_timestamps() {
+        let metrics = parse("test value=1 -1000000000\ntest value=2 9223372036854775807").unwrap();
+        assert_eq!(Some(-1_000_000_000), metrics[0].timestamp);
+        assert_eq!(Some(i64::MAX as i128), metrics[1].timestamp);
+        // The line is written back as it was.
+        assert_eq!("test value=1 -1000000000", metrics[0].to_string());
+
+        let error = parse("test value=1 9223372036854775808").unwrap_err();
+        assert!(error.message.starts_with("invalid timestamp"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |input: &str| {
+            let error = parse(input).unwrap_err();
+            (error.line, error.column, error.message)
+        };
+
+        assert_eq!(
+            (2, 10, "missing field value".to_owned()),
+            error("test value=1\ntest one=,two=2")
+        );
+        assert_eq!(
+            (1, 1, "missing measurement".to_owned()),
+            error(",tag=a value=1")
+        );
+        assert_eq!(
+            (1, 9, "expected '=', found ' '".to_owned()),
+            error("test,tag value=1")
+        );
+        assert_eq!(
+            (1, 17, "unterminated string field value".to_owned()),
+            error("test value=\"open")
+        );
+        assert_eq!(
+            (1, 12, "invalid field value \"NaN\"".to_owned()),
+            error("test value=NaN")
+        );
+        assert_eq!(
+            (1, 15, "unexpected character ' '".to_owned()),
+            error("test value=1 1 2")
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+
+use crate::error::{ErrorHandler, InfluxError};
+
+/// Reports failed flushes to the configured [`ErrorHandler`] and to the log.
+///
+/// The handler sees every failure, while log records are rate-limited to one per `interval`,
+/// stating how many failures were suppressed in between.
+pub(crate) struct ErrorReporter {
+    handler: Option<ErrorHandler>,
+    interval: Duration,
+    state: Mutex<ReporterState>,
+}
+
+#[derive(Default)]
+struct ReporterState {
+    last_logged: Option<Instant>,
+    suppressed: usize,
+    failures: usize,
+}
+
+impl ErrorReporter {
+    pub(crate) fn new(handler: Option<ErrorHandler>, interval: Duration) -> Self {
+        ErrorReporter {
+            handler,
+            interval,
+            state: Mutex::new(ReporterState::default()),
+        }
+    }
+
+    /// Reports a failed write, returns whether it was logged.
+    pub(crate) fn failure(&self, err: &InfluxError) -> bool {
+        if let Some(ref handler) = self.handler {
+            handler(err);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        let now = Instant::now();
+        match state.last_logged {
+            Some(last_logged) if now.duration_since(last_logged) < self.interval => {
+                state.suppressed += 1;
+                false
+            }
+            _ => {
+                if state.suppressed > 0 {
+                    error!(
+                        "Failed to write metrics to InfluxDB: {err} ({} similar errors suppressed)",
+                        state.suppressed
+                    );
+                } else {
+                    error!("Failed to write metrics to InfluxDB: {err}");
+                }
+                state.last_logged = Some(now);
+                state.suppressed = 0;
+                true
+            }
+        }
+    }
+
+    /// Reports a flush where every write succeeded.
+    pub(crate) fn success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.failures > 0 {
+            info!(
+                "Writing metrics to InfluxDB recovered after {} failed writes",
+                state.failures
+            );
+            *state = ReporterState::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::error::ApiError;
+    use crate::report::*;
+
+    #[test]
+    fn test_failure_rate_limited() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let handler: ErrorHandler = Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let reporter = ErrorReporter::new(Some(handler), Duration::from_secs(3600));
+        let err = InfluxError::BadRequest {
+            error: ApiError::default(),
+        };
+
+        assert!(reporter.failure(&err));
+        assert!(!reporter.failure(&err));
+        assert!(!reporter.failure(&err));
+        assert_eq!(3, handled.load(Ordering::Relaxed));
+
+        // A successful flush resets the rate limit.
+        reporter.success();
+        assert!(reporter.failure(&err));
+        assert_eq!(4, handled.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use derive_builder::Builder;
+use tokio_retry::strategy::{jitter, ExponentialBackoff};
+
+use crate::error::InfluxError;
+
+/// Controls how failed writes are retried.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Builder)]
+#[builder(setter(into))]
+#[builder(default)]
+pub struct RetryPolicy {
+    /// Total number of attempts per write, including the first one.
+    pub(crate) max_attempts: usize,
+    /// Delay before the first retry, doubled on every following retry.
+    pub(crate) base_delay: Duration,
+    /// Upper bound for the delay between two attempts.
+    pub(crate) max_delay: Duration,
+    /// Whether to randomize every delay between zero and its computed value.
+    pub(crate) jitter: bool,
+    /// Whether to retry when the server could not be reached.
+    pub(crate) retry_connection_errors: bool,
+    /// Whether to retry when the server answers with 429 Too Many Requests.
+    pub(crate) retry_too_many_requests: bool,
+    /// Whether to retry when the server answers with a 5xx status.
+    pub(crate) retry_server_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: true,
+            retry_connection_errors: true,
+            retry_too_many_requests: true,
+            retry_server_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the delays to wait before each retry, one per attempt after the first.
+    pub(crate) fn delays(&self) -> impl Iterator<Item = Duration> {
+        // `ExponentialBackoff` multiplies its base with itself, start at 2 and scale it back down
+        // so that the delays are `base_delay`, `2 * base_delay`, `4 * base_delay`, ...
+        let factor = self.base_delay.as_millis().max(1) as u64;
+        let max_delay = self.max_delay;
+        let use_jitter = self.jitter;
+        ExponentialBackoff::from_millis(2)
+            .factor(factor)
+            .map(move |delay| (delay / 2).min(max_delay))
+            .map(move |delay| if use_jitter { jitter(delay) } else { delay })
+            .take(self.max_attempts.saturating_sub(1))
+    }
+
+    /// Returns the delay to wait before retrying a write that failed with `err`: the `Retry-After`
+    /// delay sent by the server, capped at `max_delay`, or else the `backoff` delay.
+    pub(crate) fn delay(&self, err: &InfluxError, backoff: Duration) -> Duration {
+        err.retry_after()
+            .map_or(backoff, |retry_after| retry_after.min(self.max_delay))
+    }
+
+    /// Whether a write that failed with `err` should be attempted again.
+    pub(crate) fn is_retryable(&self, err: &InfluxError) -> bool {
+        match err {
+            InfluxError::ConnectionError(_) => self.retry_connection_errors,
+            InfluxError::TooManyRequests { .. } => self.retry_too_many_requests,
+            InfluxError::ServerError { .. } => self.retry_server_errors,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::ApiError;
+    use crate::retry::*;
+
+    #[test]
+    fn test_delays() {
+        let policy = RetryPolicyBuilder::default()
+            .max_attempts(5usize)
+            .base_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_millis(500))
+            .jitter(false)
+            .build()
+            .unwrap();
+
+        let expected = vec![
+            Duration::from_millis(100),
+            Duration::from_millis(200),
+            Duration::from_millis(400),
+            Duration::from_millis(500),
+        ];
+        assert_eq!(expected, policy.delays().collect::<Vec<Duration>>());
+    }
+
+    #[test]
+    fn test_delays_with_jitter() {
+        let policy = RetryPolicy::default();
+        let delays = policy.delays().collect::<Vec<Duration>>();
+        assert_eq!(2, delays.len());
+        assert!(delays[0] <= Duration::from_millis(100));
+        assert!(delays[1] <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let policy = RetryPolicy::default();
+        let error = || ApiError::default();
+
+        assert!(policy.is_retryable(&InfluxError::TooManyRequests {
+            error: error(),
+            retry_after: None
+        }));
+        assert!(policy.is_retryable(&InfluxError::ServerError {
+            error: error(),
+            retry_after: None
+        }));
+        assert!(!policy.is_retryable(&InfluxError::AuthenticationError { error: error() }));
+        assert!(!policy.is_retryable(&InfluxError::AuthorizationError { error: error() }));
+        assert!(!policy.is_retryable(&InfluxError::BadRequest { error: error() }));
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicyBuilder::default()
+            .max_delay(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let error = |retry_after| InfluxError::TooManyRequests {
+            error: ApiError::default(),
+            retry_after,
+        };
+        let backoff = Duration::from_millis(100);
+
+        assert_eq!(backoff, policy.delay(&error(None), backoff));
+        assert_eq!(
+            Duration::from_secs(2),
+            policy.delay(&error(Some(Duration::from_secs(2))), backoff)
+        );
+        assert_eq!(
+            Duration::from_secs(10),
+            policy.delay(&error(Some(Duration::from_secs(86400))), backoff)
+        );
+    }
+}
@@ -0,0 +1,1008 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::ops::Range;
+use std::sync::{Mutex, MutexGuard};
+
+use bytes::{Bytes, BytesMut};
+use dashmap::mapref::one::Ref;
+use dashmap::DashMap;
+use metrics::Key;
+use metrics_util::MetricKind;
+use quanta::Instant;
+
+use crate::config::{
+    MeasurementNaming, NonFiniteFloats, QuantileNaming, RecorderConfig, UnitPlacement,
+};
+use crate::distribution::Distribution;
+use crate::metric::{
+    write_escaped, write_prefix, write_string_field, write_value, LineFormat, Metadata, Timestamp,
+    KEY_SPECIAL,
+};
+use crate::types::Type;
+
+/// Writes registry handles as line protocol into a buffer that is reused from one flush to the
+/// next.
+///
+/// The escaped measurement, tags and field of every key are cached the first time the key is
+/// written, after that a line only costs the formatting of its values.
+pub(crate) struct LineSerializer {
+    format: LineFormat,
+    naming: QuantileNaming,
+    prefix: Option<String>,
+    measurement_naming: MeasurementNaming,
+    global_tags: Vec<(String, String)>,
+    group_fields: bool,
+    unit_placement: UnitPlacement,
+    /// What metrics were described with, by name.
+    metadata: DashMap<String, Metadata>,
+    keys: DashMap<Key, CachedKey>,
+    buffer: Mutex<BytesMut>,
+}
+
+/// The escaped parts of the lines written for a key.
+struct CachedKey {
+    /// The measurement and tags.
+    prefix: Box<str>,
+    /// The field holding the value, fields of histograms are prefixed with it.
+    field: Option<Box<str>>,
+    /// The unit appended to every field.
+    unit: Option<Box<str>>,
+}
+
+/// Lines written by a [`LineWriter`], separated by newlines in `buffer`.
+pub(crate) struct Lines {
+    pub(crate) buffer: Bytes,
+    /// The range of every line in `buffer`, without its newline.
+    pub(crate) ranges: Vec<Range<usize>>,
+    /// How many non-finite float values were left out.
+    pub(crate) dropped_values: usize,
+}
+
+impl Lines {
+    /// Returns the consecutive lines in `ranges` joined with newlines, without copying them.
+    pub(crate) fn join(&self, ranges: &[Range<usize>]) -> Bytes {
+        match (ranges.first(), ranges.last()) {
+            (Some(first), Some(last)) => self.buffer.slice(first.start..last.end),
+            _ => Bytes::new(),
+        }
+    }
+}
+
+impl LineSerializer {
+    pub(crate) fn new(format: LineFormat, config: &RecorderConfig) -> Self {
+        LineSerializer {
+            format,
+            naming: config.quantile_naming.clone(),
+            prefix: config.prefix.clone(),
+            measurement_naming: config.measurement_naming.clone(),
+            global_tags: config.global_tags.clone(),
+            group_fields: config.group_fields,
+            unit_placement: config.unit_placement.clone(),
+            metadata: DashMap::new(),
+            keys: DashMap::new(),
+            buffer: Mutex::new(BytesMut::new()),
+        }
+    }
+
+    pub(crate) fn format(&self) -> &LineFormat {
+        &self.format
+    }
+
+    /// Stores what a metric was described with, replacing what it was described with before.
+    pub(crate) fn describe(&self, metadata: Metadata) {
+        let name = metadata.name.clone();
+        self.metadata.insert(name.clone(), metadata);
+        // The unit may be part of the lines of the keys of that metric.
+        self.keys.retain(|key, _| key.name() != name);
+    }
+
+    /// Returns what every described metric was described with, sorted by name.
+    pub(crate) fn metadata(&self) -> Vec<Metadata> {
+        let mut metadata = self
+            .metadata
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect::<Vec<Metadata>>();
+        metadata.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        metadata
+    }
+
+    /// Drops the cached parts of `key`, which is not written anymore.
+    pub(crate) fn forget(&self, key: &Key) {
+        self.keys.remove(key);
+    }
+
+    /// Returns the cached parts of `key`, computing them the first time.
+    fn key(&self, key: &Key) -> Ref<'_, Key, CachedKey> {
+        if let Some(cached) = self.keys.get(key) {
+            return cached;
+        }
+        self.keys
+            .entry(key.clone())
+            .or_insert_with(|| self.cache_key(key))
+            .downgrade()
+    }
+
+    /// Returns the name of the metric `name` once prefixed.
+    fn prefixed<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match self.prefix {
+            Some(ref prefix) => Cow::Owned(format!("{prefix}{name}")),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    /// Returns the measurement, name tag and field that the prefixed `name` maps to.
+    fn naming<'a>(&'a self, name: &'a str) -> Naming<'a> {
+        let (measurement, name_tag, field) = match self.measurement_naming {
+            MeasurementNaming::PerMetric => (name, None, None),
+            MeasurementNaming::Split => match name.split_once('.') {
+                Some((measurement, field)) if !measurement.is_empty() && !field.is_empty() => {
+                    (measurement, None, Some(field.replace('.', "_")))
+                }
+                _ => (name, None, None),
+            },
+            MeasurementNaming::Single {
+                ref measurement,
+                ref tag,
+            } => (measurement.as_str(), Some((tag.as_str(), name)), None),
+        };
+        Naming {
+            measurement,
+            name_tag,
+            field,
+        }
+    }
+
+    fn cache_key(&self, key: &Key) -> CachedKey {
+        let name = self.prefixed(key.name());
+        let Naming {
+            measurement,
+            name_tag,
+            field,
+        } = self.naming(&name);
+
+        let unit = self
+            .metadata
+            .get(key.name())
+            .and_then(|metadata| metadata.unit);
+        let (unit_tag, unit) = match (&self.unit_placement, &unit) {
+            (UnitPlacement::Tag(tag), Some(unit)) => (Some((tag.as_str(), unit.as_str())), None),
+            (UnitPlacement::FieldSuffix, Some(unit)) => {
+                (None, Some(format!("_{}", unit.as_str()).into_boxed_str()))
+            }
+            _ => (None, None),
+        };
+
+        // A label named like the name tag is renamed rather than hidden by it.
+        let renamed = name_tag.map(|(tag, _)| format!("{tag}_label"));
+        let labels = key.labels().map(|label| match (name_tag, &renamed) {
+            (Some((tag, _)), Some(renamed)) if label.key() == tag => {
+                (renamed.as_str(), label.value())
+            }
+            _ => (label.key(), label.value()),
+        });
+        let global_tags = self
+            .global_tags
+            .iter()
+            .map(|(tag, value)| (tag.as_str(), value.as_str()));
+        let tags = name_tag
+            .into_iter()
+            .chain(labels)
+            .chain(unit_tag)
+            .chain(global_tags);
+        let mut prefix = String::new();
+        write_prefix(&mut prefix, measurement, tags).expect("writing to a String cannot fail");
+
+        let field = field.map(|field| {
+            let mut escaped = String::new();
+            write_escaped(&mut escaped, &field, KEY_SPECIAL)
+                .expect("writing to a String cannot fail");
+            escaped.into_boxed_str()
+        });
+        CachedKey {
+            prefix: prefix.into_boxed_str(),
+            field,
+            unit,
+        }
+    }
+
+    /// Starts writing lines that all carry `timestamp`.
+    ///
+    /// The buffer stays locked until the writer is finished, concurrent flushes wait for each
+    /// other. Its memory is reclaimed once the lines of the previous flush are dropped.
+    pub(crate) fn writer(&self, timestamp: Timestamp) -> LineWriter<'_> {
+        LineWriter {
+            serializer: self,
+            buffer: self.buffer.lock().unwrap(),
+            timestamp: self
+                .format
+                .precision
+                .convert_nanoseconds(timestamp.nanoseconds()),
+            ranges: vec![],
+            parts: vec![],
+            dropped_values: 0,
+        }
+    }
+}
+
+/// Where the lines of a metric are written.
+struct Naming<'a> {
+    measurement: &'a str,
+    /// The tag holding the name of the metric, and that name.
+    name_tag: Option<(&'a str, &'a str)>,
+    field: Option<String>,
+}
+
+pub(crate) struct LineWriter<'a> {
+    serializer: &'a LineSerializer,
+    buffer: MutexGuard<'a, BytesMut>,
+    timestamp: i128,
+    ranges: Vec<Range<usize>>,
+    /// The parts of every line in `ranges`, only kept when fields are grouped.
+    parts: Vec<LineParts>,
+    dropped_values: usize,
+}
+
+/// Where the parts of a line are, to group the fields of lines sharing their measurement and tags.
+struct LineParts {
+    /// The length of the measurement and tags at the start of the line.
+    prefix: usize,
+    /// The length of the field of a counter or a gauge, which is the only one of its line.
+    value_field: Option<usize>,
+}
+
+/// The field of counters and gauges whose key does not have one.
+const VALUE_FIELD: &str = "value";
+
+impl LineWriter<'_> {
+    pub(crate) fn counter(&mut self, key: &Key, value: u64) {
+        self.line(key, true, |fields| fields.value(value));
+    }
+
+    pub(crate) fn gauge(&mut self, key: &Key, value: f64) {
+        self.line(key, true, |fields| fields.value(value));
+    }
+
+    /// Writes a distribution, summaries are snapshotted at the current instant.
+    pub(crate) fn distribution(&mut self, key: &Key, distribution: &Distribution) {
+        let naming = &self.serializer.naming;
+        self.line(key, false, |fields| match distribution {
+            Distribution::Histogram(histogram) => {
+                fields.field("sum", histogram.sum())?;
+                fields.field("count", histogram.count())?;
+                for (bound, count) in histogram.buckets() {
+                    fields.bucket(bound, count)?;
+                }
+                Ok(())
+            }
+            Distribution::Summary(summary, quantiles, sum) => {
+                let snapshot = summary.snapshot(Instant::now());
+                let count = summary.count();
+                fields.field("sum", *sum)?;
+                fields.field("count", count)?;
+                if !summary.is_empty() {
+                    fields.field("mean", *sum / count as f64)?;
+                }
+                if !snapshot.is_empty() {
+                    fields.field("min", snapshot.min())?;
+                    fields.field("max", snapshot.max())?;
+                }
+                for (index, quantile) in quantiles.iter().enumerate() {
+                    let Some(value) = snapshot.quantile(quantile.value()) else {
+                        continue;
+                    };
+                    let field = naming.field(quantile);
+                    // The 0 and 1 quantiles are labelled `min` and `max`, which are already there.
+                    if field == "min"
+                        || field == "max"
+                        || quantiles[..index]
+                            .iter()
+                            .any(|previous| naming.field(previous) == field)
+                    {
+                        continue;
+                    }
+                    fields.field(&field, value)?;
+                }
+                Ok(())
+            }
+        });
+    }
+
+    /// Writes what a metric was described with to `measurement`.
+    pub(crate) fn metadata(&mut self, measurement: &str, metadata: &Metadata) {
+        let start = self.buffer.len();
+        let kind = match metadata.kind {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        };
+        let global_tags = self
+            .serializer
+            .global_tags
+            .iter()
+            .map(|(tag, value)| (tag.as_str(), value.as_str()));
+        // The metric is named the way its lines are, along with where these lines are written.
+        let name = self.serializer.prefixed(&metadata.name);
+        let naming = self.serializer.naming(&name);
+        let measurement_tag = (naming.measurement != name).then_some(naming.measurement);
+        let tags = [("name", &*name), ("kind", kind)]
+            .into_iter()
+            .chain(measurement_tag.map(|measurement| ("measurement", measurement)))
+            .chain(naming.field.as_deref().map(|field| ("field", field)))
+            .chain(global_tags);
+        write_prefix(&mut *self.buffer, measurement, tags)
+            .expect("writing to a buffer cannot fail");
+        self.buffer.extend_from_slice(b" description=");
+        write_string_field(&mut *self.buffer, &metadata.description)
+            .expect("writing to a buffer cannot fail");
+        if let Some(unit) = metadata.unit {
+            self.buffer.extend_from_slice(b",unit=");
+            write_string_field(&mut *self.buffer, unit.as_str())
+                .expect("writing to a buffer cannot fail");
+        }
+        // Metadata lines are never grouped.
+        let parts = LineParts {
+            prefix: 0,
+            value_field: None,
+        };
+        self.end_line(start, parts);
+    }
+
+    pub(crate) fn finish(mut self) -> Lines {
+        if self.serializer.group_fields {
+            let lines = self.buffer.split().freeze();
+            let ranges = std::mem::take(&mut self.ranges);
+            self.group_fields(&lines, &ranges);
+        }
+        Lines {
+            buffer: self.buffer.split().freeze(),
+            ranges: self.ranges,
+            dropped_values: self.dropped_values,
+        }
+    }
+
+    /// Writes `lines` again, merging the counters and gauges sharing their measurement and tags
+    /// into a single line with a field each.
+    ///
+    /// A metric whose field is already in the merged line starts a new one instead.
+    fn group_fields(&mut self, lines: &Bytes, ranges: &[Range<usize>]) {
+        // Lines all end with the same timestamp, preceded by a space.
+        let suffix = self.timestamp.unsigned_abs().checked_ilog10().unwrap_or(0) as usize
+            + 2
+            + usize::from(self.timestamp < 0);
+        let field = |index: usize| {
+            let start = ranges[index].start + self.parts[index].prefix + 1;
+            &lines[start..start + self.parts[index].value_field.unwrap_or(0)]
+        };
+
+        let mut groups: Vec<Vec<usize>> = vec![];
+        let mut open: HashMap<&[u8], usize> = HashMap::new();
+        for (index, (range, parts)) in ranges.iter().zip(&self.parts).enumerate() {
+            if parts.value_field.is_none() {
+                groups.push(vec![index]);
+                continue;
+            }
+            let prefix = &lines[range.start..range.start + parts.prefix];
+            match open.get(prefix) {
+                Some(&group)
+                    if !groups[group]
+                        .iter()
+                        .any(|&other| field(other) == field(index)) =>
+                {
+                    groups[group].push(index)
+                }
+                _ => {
+                    open.insert(prefix, groups.len());
+                    groups.push(vec![index]);
+                }
+            }
+        }
+
+        for group in groups {
+            let start = self.buffer.len();
+            for (position, &index) in group.iter().enumerate() {
+                let line = &lines[ranges[index].clone()];
+                if position == 0 {
+                    self.buffer.extend_from_slice(&line[..line.len() - suffix]);
+                } else {
+                    self.buffer.extend_from_slice(b",");
+                    self.buffer.extend_from_slice(
+                        &line[self.parts[index].prefix + 1..line.len() - suffix],
+                    );
+                }
+            }
+            let line = &lines[ranges[group[0]].clone()];
+            self.buffer.extend_from_slice(&line[line.len() - suffix..]);
+            self.ranges.push(start..self.buffer.len());
+            self.buffer.extend_from_slice(b"\n");
+        }
+    }
+
+    /// Writes a line for `key`, or nothing when the [`NonFiniteFloats`] policy drops it.
+    ///
+    /// `value` tells whether the line is the one of a counter or a gauge, with a single field.
+    fn line(
+        &mut self,
+        key: &Key,
+        value: bool,
+        write_fields: impl FnOnce(&mut Fields<'_>) -> std::fmt::Result,
+    ) {
+        let start = self.buffer.len();
+        let cached = self.serializer.key(key);
+        self.buffer.extend_from_slice(cached.prefix.as_bytes());
+        let mut fields = Fields {
+            buffer: &mut self.buffer,
+            format: &self.serializer.format,
+            field: cached.field.as_deref(),
+            unit: cached.unit.as_deref(),
+            written: 0,
+            dropped: 0,
+            drop_line: false,
+        };
+        write_fields(&mut fields).expect("writing to a buffer cannot fail");
+
+        self.dropped_values += fields.dropped;
+        if fields.drop_line || fields.written == 0 {
+            self.buffer.truncate(start);
+            return;
+        }
+        let field = cached.field.as_deref().unwrap_or(VALUE_FIELD).len()
+            + cached.unit.as_deref().map_or(0, str::len);
+        let parts = LineParts {
+            prefix: cached.prefix.len(),
+            value_field: value.then_some(field),
+        };
+        drop(cached);
+        self.end_line(start, parts);
+    }
+
+    /// Ends the line started at `start` with the timestamp.
+    fn end_line(&mut self, start: usize, parts: LineParts) {
+        write!(self.buffer, " {}", self.timestamp).expect("writing to a buffer cannot fail");
+        self.ranges.push(start..self.buffer.len());
+        self.buffer.extend_from_slice(b"\n");
+        if self.serializer.group_fields {
+            self.parts.push(parts);
+        }
+    }
+}
+
+/// The fields of the line being written.
+struct Fields<'a> {
+    buffer: &'a mut BytesMut,
+    format: &'a LineFormat,
+    /// The escaped field of the key, if it has one.
+    field: Option<&'a str>,
+    /// The unit appended to every field, if any.
+    unit: Option<&'a str>,
+    written: usize,
+    dropped: usize,
+    drop_line: bool,
+}
+
+impl Fields<'_> {
+    /// Writes the value of a counter or a gauge.
+    fn value(&mut self, value: impl Into<Type>) -> std::fmt::Result {
+        let Some(value) = self.check(value.into()) else {
+            return Ok(());
+        };
+        self.separator()?;
+        self.buffer.write_str(self.field.unwrap_or(VALUE_FIELD))?;
+        self.unit()?;
+        self.buffer.write_char('=')?;
+        write_value(self.buffer, &value, self.format)
+    }
+
+    /// Writes one of the values of a distribution.
+    fn field(&mut self, name: &str, value: impl Into<Type>) -> std::fmt::Result {
+        let Some(value) = self.check(value.into()) else {
+            return Ok(());
+        };
+        self.separator()?;
+        self.key_field()?;
+        write_escaped(self.buffer, name, KEY_SPECIAL)?;
+        self.unit()?;
+        self.buffer.write_char('=')?;
+        write_value(self.buffer, &value, self.format)
+    }
+
+    /// Writes the count of a histogram bucket, keyed by its upper bound.
+    fn bucket(&mut self, bound: f64, count: u64) -> std::fmt::Result {
+        self.separator()?;
+        self.key_field()?;
+        // A formatted float never contains a character that needs escaping. Bounds keep their two
+        // decimals unless that would round them, so that distinct bounds get distinct fields.
+        if (bound * 100.0).round() / 100.0 == bound {
+            write!(self.buffer, "{bound:.2}")?;
+        } else {
+            write!(self.buffer, "{bound}")?;
+        }
+        self.unit()?;
+        self.buffer.write_char('=')?;
+        write_value(self.buffer, &count.into(), self.format)
+    }
+
+    /// Applies the [`NonFiniteFloats`] policy to `value`, returning `None` if it is left out.
+    fn check(&mut self, value: Type) -> Option<Type> {
+        match value {
+            Type::Float(float) if !float.is_finite() => {
+                let replacement = self.format.non_finite_floats.replace(float);
+                if replacement.is_none() {
+                    self.dropped += 1;
+                    self.drop_line |= self.format.non_finite_floats == NonFiniteFloats::DropLine;
+                }
+                replacement.map(Type::Float)
+            }
+            value => Some(value),
+        }
+    }
+
+    fn separator(&mut self) -> std::fmt::Result {
+        let separator = if self.written == 0 { ' ' } else { ',' };
+        self.written += 1;
+        self.buffer.write_char(separator)
+    }
+
+    fn unit(&mut self) -> std::fmt::Result {
+        match self.unit {
+            Some(unit) => self.buffer.write_str(unit),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the field of the key in front of the name of a distribution value, if it has one.
+    fn key_field(&mut self) -> std::fmt::Result {
+        match self.field {
+            Some(field) => write!(self.buffer, "{field}_"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use metrics::{Label, Unit};
+    use metrics_util::parse_quantiles;
+
+    use crate::config::{Precision, RecorderConfigBuilder};
+    use crate::metric::LineFormatBuilder;
+    use crate::parser::parse;
+
+    use crate::serializer::*;
+
+    fn serializer(format: LineFormat) -> LineSerializer {
+        LineSerializer::new(format, &RecorderConfig::default())
+    }
+
+    fn lines(lines: &Lines) -> Vec<&str> {
+        lines
+            .ranges
+            .iter()
+            .map(|range| std::str::from_utf8(&lines.buffer[range.clone()]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_counters_and_gauges() {
+        let serializer = serializer(LineFormat::default());
+        let mut writer = serializer.writer(Duration::from_secs(1).into());
+        let key = Key::from_parts("requests", vec![Label::new("path", "/")]);
+        writer.counter(&key, 5);
+        writer.counter(&key, 42);
+        writer.gauge(&Key::from_name("temperature"), -1.5);
+        let written = writer.finish();
+
+        assert_eq!(
+            vec![
+                "requests,path=/ value=5i 1000000000",
+                "requests,path=/ value=42i 1000000000",
+                "temperature value=-1.5 1000000000",
+            ],
+            lines(&written)
+        );
+        let joined = lines(&written).join("\n");
+        assert_eq!(format!("{joined}\n").as_bytes(), &written.buffer[..]);
+        assert_eq!(joined.as_bytes(), &written.join(&written.ranges)[..]);
+        assert_eq!(2, serializer.keys.len());
+    }
+
+    #[test]
+    fn test_escape_prefix() {
+        let serializer = serializer(LineFormat::default());
+        let mut writer = serializer.writer(Timestamp::from(Duration::ZERO));
+        let key = Key::from_parts(
+            "my measurement",
+            vec![Label::new("my tag", "a=b"), Label::new("empty", "")],
+        );
+        writer.counter(&key, 1);
+
+        assert_eq!(
+            vec!["my\\ measurement,my\\ tag=a\\=b value=1i 0"],
+            lines(&writer.finish())
+        );
+    }
+
+    #[test]
+    fn test_global_tags() {
+        let config = RecorderConfigBuilder::default()
+            .global_tag("host", "server-01")
+            .global_tag("env", "production")
+            .build()
+            .unwrap();
+        let serializer = LineSerializer::new(LineFormat::default(), &config);
+        let mut writer = serializer.writer(Duration::ZERO.into());
+        writer.counter(&Key::from_name("requests"), 1);
+        writer.counter(
+            &Key::from_parts(
+                "errors",
+                vec![Label::new("env", "staging"), Label::new("host", "")],
+            ),
+            1,
+        );
+
+        assert_eq!(
+            vec![
+                "requests,host=server-01,env=production value=1i 0",
+                "errors,env=staging,host=server-01 value=1i 0",
+            ],
+            lines(&writer.finish())
+        );
+    }
+
+    #[test]
+    fn test_measurement_naming() {
+        let mut distribution = Distribution::new_histogram(&[1.0]);
+        distribution.record_samples(&[(0.5, Instant::now())]);
+        let key = Key::from_parts("http.requests.total", vec![Label::new("name", "label")]);
+
+        for (prefix, naming, expected) in [
+            (
+                None,
+                MeasurementNaming::PerMetric,
+                vec![
+                    "http.requests.total,name=label value=1i 0",
+                    "uptime value=2 0",
+                    "http.latency sum=0.5,count=1i,1.00=1i 0",
+                ],
+            ),
+            (
+                Some("myapp_"),
+                MeasurementNaming::Split,
+                vec![
+                    "myapp_http,name=label requests_total=1i 0",
+                    "myapp_uptime value=2 0",
+                    "myapp_http latency_sum=0.5,latency_count=1i,latency_1.00=1i 0",
+                ],
+            ),
+            (
+                None,
+                MeasurementNaming::Single {
+                    measurement: "app".to_owned(),
+                    tag: "name".to_owned(),
+                },
+                vec![
+                    "app,name=http.requests.total,name_label=label value=1i 0",
+                    "app,name=uptime value=2 0",
+                    "app,name=http.latency sum=0.5,count=1i,1.00=1i 0",
+                ],
+            ),
+        ] {
+            let config = RecorderConfigBuilder::default()
+                .prefix(prefix.map(str::to_owned))
+                .measurement_naming(naming)
+                .build()
+                .unwrap();
+            let serializer = LineSerializer::new(LineFormat::default(), &config);
+            let mut writer = serializer.writer(Duration::ZERO.into());
+            writer.counter(&key, 1);
+            writer.gauge(&Key::from_name("uptime"), 2.0);
+            writer.distribution(&Key::from_name("http.latency"), &distribution);
+
+            assert_eq!(expected, lines(&writer.finish()));
+        }
+    }
+
+    #[test]
+    fn test_group_fields() {
+        let config = RecorderConfigBuilder::default()
+            .measurement_naming(MeasurementNaming::Split)
+            .group_fields(true)
+            .build()
+            .unwrap();
+        let serializer = LineSerializer::new(LineFormat::default(), &config);
+        let mut distribution = Distribution::new_histogram(&[1.0]);
+        distribution.record_samples(&[(0.5, Instant::now())]);
+
+        let mut writer = serializer.writer(Duration::from_secs(1).into());
+        writer.gauge(&Key::from_name("process.cpu"), 0.5);
+        writer.counter(
+            &Key::from_parts("http.requests", vec![Label::new("code", "200")]),
+            3,
+        );
+        writer.gauge(&Key::from_name("process.mem"), 1024.0);
+        writer.gauge(&Key::from_name("process.cpu"), 0.75);
+        writer.counter(
+            &Key::from_parts("http.requests", vec![Label::new("code", "500")]),
+            1,
+        );
+        writer.distribution(&Key::from_name("process.latency"), &distribution);
+        writer.gauge(&Key::from_name("process.fds"), f64::NAN);
+        writer.gauge(&Key::from_name("process.fds"), 12.0);
+        let written = writer.finish();
+
+        assert_eq!(
+            vec![
+                "process cpu=0.5,mem=1024 1000000000",
+                "http,code=200 requests=3i 1000000000",
+                "process cpu=0.75,fds=12 1000000000",
+                "http,code=500 requests=1i 1000000000",
+                "process latency_sum=0.5,latency_count=1i,latency_1.00=1i 1000000000",
+            ],
+            lines(&written)
+        );
+        assert_eq!(1, written.dropped_values);
+        let joined = lines(&written).join("\n");
+        assert_eq!(format!("{joined}\n").as_bytes(), &written.buffer[..]);
+    }
+
+    fn metadata(name: &str, kind: MetricKind, unit: Option<Unit>) -> Metadata {
+        Metadata {
+            name: name.to_owned(),
+            kind,
+            unit,
+            description: format!("The \"{name}\" metric"),
+        }
+    }
+
+    #[test]
+    fn test_units() {
+        let mut distribution = Distribution::new_histogram(&[1.0]);
+        distribution.record_samples(&[(0.5, Instant::now())]);
+
+        for (placement, expected) in [
+            (
+                UnitPlacement::Omit,
+                vec!["requests value=1i 0", "latency sum=0.5,count=1i,1.00=1i 0"],
+            ),
+            (
+                UnitPlacement::Tag("unit".to_owned()),
+                vec![
+                    "requests,unit=count value=1i 0",
+                    "latency,unit=seconds sum=0.5,count=1i,1.00=1i 0",
+                ],
+            ),
+            (
+                UnitPlacement::FieldSuffix,
+                vec![
+                    "requests value_count=1i 0",
+                    "latency sum_seconds=0.5,count_seconds=1i,1.00_seconds=1i 0",
+                ],
+            ),
+        ] {
+            let config = RecorderConfigBuilder::default()
+                .unit_placement(placement)
+                .build()
+                .unwrap();
+            let serializer = LineSerializer::new(LineFormat::default(), &config);
+            serializer.describe(metadata("requests", MetricKind::Counter, Some(Unit::Count)));
+
+            let mut writer = serializer.writer(Duration::ZERO.into());
+            writer.counter(&Key::from_name("requests"), 1);
+            writer.distribution(&Key::from_name("latency"), &distribution);
+            drop(writer.finish());
+            // Describing a metric that was already written updates its lines.
+            serializer.describe(metadata(
+                "latency",
+                MetricKind::Histogram,
+                Some(Unit::Seconds),
+            ));
+
+            let mut writer = serializer.writer(Duration::ZERO.into());
+            writer.counter(&Key::from_name("requests"), 1);
+            writer.distribution(&Key::from_name("latency"), &distribution);
+            assert_eq!(expected, lines(&writer.finish()));
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        let config = RecorderConfigBuilder::default()
+            .global_tag("host", "server-01")
+            .build()
+            .unwrap();
+        let serializer = LineSerializer::new(LineFormat::default(), &config);
+        serializer.describe(metadata("requests", MetricKind::Counter, None));
+        serializer.describe(metadata(
+            "latency",
+            MetricKind::Histogram,
+            Some(Unit::Seconds),
+        ));
+        serializer.describe(metadata("requests", MetricKind::Counter, Some(Unit::Count)));
+
+        let metadata = serializer.metadata();
+        assert_eq!(
+            vec![
+                ("latency", Some(Unit::Seconds)),
+                ("requests", Some(Unit::Count))
+            ],
+            metadata
+                .iter()
+                .map(|metadata| (metadata.name.as_str(), metadata.unit))
+                .collect::<Vec<(&str, Option<Unit>)>>()
+        );
+
+        let mut writer = serializer.writer(Duration::ZERO.into());
+        for metadata in &metadata {
+            writer.metadata("metrics_metadata", metadata);
+        }
+        assert_eq!(
+            vec![
+                "metrics_metadata,name=latency,kind=histogram,host=server-01 description=\"The \\\"latency\\\" metric\",unit=\"seconds\" 0",
+                "metrics_metadata,name=requests,kind=counter,host=server-01 description=\"The \\\"requests\\\" metric\",unit=\"count\" 0",
+            ],
+            lines(&writer.finish())
+        );
+    }
+
+    #[test]
+    fn test_metadata_naming() {
+        let config = RecorderConfigBuilder::default()
+            .prefix(Some("myapp_".to_owned()))
+            .measurement_naming(MeasurementNaming::Split)
+            .build()
+            .unwrap();
+        let serializer = LineSerializer::new(LineFormat::default(), &config);
+
+        let mut writer = serializer.writer(Duration::ZERO.into());
+        writer.counter(&Key::from_name("http.requests"), 1);
+        writer.metadata(
+            "metrics_metadata",
+            &metadata("http.requests", MetricKind::Counter, None),
+        );
+        writer.metadata(
+            "metrics_metadata",
+            &metadata("uptime", MetricKind::Gauge, None),
+        );
+        assert_eq!(
+            vec![
+                "myapp_http requests=1i 0",
+                "metrics_metadata,name=myapp_http.requests,kind=counter,measurement=myapp_http,field=requests description=\"The \\\"http.requests\\\" metric\" 0",
+                "metrics_metadata,name=myapp_uptime,kind=gauge description=\"The \\\"uptime\\\" metric\" 0",
+            ],
+            lines(&writer.finish())
+        );
+    }
+
+    #[test]
+    fn test_precision() {
+        let format = LineFormatBuilder::default()
+            .precision(Precision::Seconds)
+            .unsigned_integers(true)
+            .build()
+            .unwrap();
+        let serializer = serializer(format);
+        let mut writer = serializer.writer(Duration::from_millis(2500).into());
+        writer.counter(&Key::from_name("requests"), 3);
+
+        assert_eq!(vec!["requests value=3u 2"], lines(&writer.finish()));
+    }
+
+    #[test]
+    fn test_non_finite_floats() {
+        for (policy, expected, dropped) in [
+            (NonFiniteFloats::DropField, vec!["finite value=1 0"], 2),
+            (NonFiniteFloats::DropLine, vec!["finite value=1 0"], 2),
+            (
+                NonFiniteFloats::Replace(0.0),
+                vec!["nan value=0 0", "finite value=1 0", "infinite value=0 0"],
+                0,
+            ),
+        ] {
+            let format = LineFormatBuilder::default()
+                .non_finite_floats(policy)
+                .build()
+                .unwrap();
+            let serializer = serializer(format);
+            let mut writer = serializer.writer(Duration::ZERO.into());
+            writer.gauge(&Key::from_name("nan"), f64::NAN);
+            writer.gauge(&Key::from_name("finite"), 1.0);
+            writer.gauge(&Key::from_name("infinite"), f64::INFINITY);
+            let written = writer.finish();
+
+            assert_eq!(expected, lines(&written));
+            assert_eq!(dropped, written.dropped_values);
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let quantiles = Arc::new(parse_quantiles(&[0.0, 0.5, 0.99, 1.0]));
+        let mut distribution = Distribution::new_summary(quantiles);
+        let now = Instant::now();
+        let samples = (1..=100)
+            .map(|sample| (sample as f64, now))
+            .collect::<Vec<(f64, Instant)>>();
+        distribution.record_samples(&samples);
+
+        let serializer = serializer(LineFormat::default());
+        let mut writer = serializer.writer(Duration::ZERO.into());
+        writer.distribution(&Key::from_name("latency"), &distribution);
+        let written = writer.finish();
+        let metric = parse(lines(&written)[0]).unwrap().remove(0);
+
+        let fields = metric
+            .fields
+            .iter()
+            .map(|(field, _)| field.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            vec!["sum", "count", "mean", "min", "max", "p50", "p99"],
+            fields
+        );
+        let value = |field: &str| match metric.fields.iter().find(|(name, _)| name == field) {
+            Some((_, Type::Float(value))) => *value,
+            _ => panic!("missing float field {field}"),
+        };
+        assert_eq!(5050.0, value("sum"));
+        assert_eq!(50.5, value("mean"));
+        assert_eq!(1.0, value("min"));
+        assert_eq!(100.0, value("max"));
+        assert!((value("p50") - 50.0).abs() <= 1.0);
+        assert!((value("p99") - 99.0).abs() <= 1.0);
+
+        let config = RecorderConfigBuilder::default()
+            .quantile_naming(QuantileNaming::Quantile)
+            .build()
+            .unwrap();
+        let serializer = LineSerializer::new(LineFormat::default(), &config);
+        let mut writer = serializer.writer(Duration::ZERO.into());
+        writer.distribution(&Key::from_name("latency"), &distribution);
+        let written = writer.finish();
+        let metric = parse(lines(&written)[0]).unwrap().remove(0);
+        let fields = metric
+            .fields
+            .iter()
+            .map(|(field, _)| field.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            vec!["sum", "count", "mean", "min", "max", "0", "0.5", "0.99", "1"],
+            fields
+        );
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut distribution = Distribution::new_histogram(&[1.0, 10.0]);
+        let now = Instant::now();
+        distribution.record_samples(&[(0.5, now), (5.0, now), (50.0, now)]);
+
+        let serializer = serializer(LineFormat::default());
+        let mut writer = serializer.writer(Duration::ZERO.into());
+        writer.distribution(&Key::from_name("latency"), &distribution);
+
+        assert_eq!(
+            vec!["latency sum=55.5,count=3i,1.00=1i,10.00=2i 0"],
+            lines(&writer.finish())
+        );
+    }
+
+    #[test]
+    fn test_histogram_small_bounds() {
+        let mut distribution = Distribution::new_histogram(&[0.001, 0.005, 0.01, 0.025]);
+        let now = Instant::now();
+        distribution.record_samples(&[(0.0005, now), (0.003, now), (0.02, now)]);
+
+        let serializer = serializer(LineFormat::default());
+        let mut writer = serializer.writer(Duration::ZERO.into());
+        writer.distribution(&Key::from_name("latency"), &distribution);
+
+        assert_eq!(
+            vec!["latency sum=0.0235,count=3i,0.001=1i,0.005=2i,0.01=2i,0.025=3i 0"],
+            lines(&writer.finish())
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use metrics::{Counter, Gauge, Histogram, Key, Label};
+use metrics_util::registry::Registry;
+use metrics_util::MetricKind;
+
+use crate::registry::GenerationalAtomicStorage;
+
+/// Metrics about the exporter itself, registered in the recorder's own registry so that they are
+/// written along with the application metrics.
+///
+/// Every handle is a no-op when no prefix is configured.
+pub(crate) struct SelfMetrics {
+    /// Keys of the exporter's own metrics in the registry, with their kind.
+    keys: HashMap<Key, MetricKind>,
+    pub(crate) flushes: Counter,
+    pub(crate) flush_failures: Counter,
+    pub(crate) lines_written: Counter,
+    pub(crate) bytes_sent: Counter,
+    pub(crate) write_duration: Histogram,
+    pub(crate) retries: Counter,
+    pub(crate) dropped_batches: Counter,
+    pub(crate) dropped_values: Counter,
+    /// Distinct series rejected for being over the limits, see [`SeriesLimiter`].
+    ///
+    /// [`SeriesLimiter`]: crate::cardinality::SeriesLimiter
+    pub(crate) rejected_series: Counter,
+    pub(crate) counters: Gauge,
+    pub(crate) gauges: Gauge,
+    pub(crate) histograms: Gauge,
+}
+
+impl SelfMetrics {
+    pub(crate) fn new(
+        registry: &Registry<Key, GenerationalAtomicStorage>,
+        prefix: Option<&str>,
+    ) -> Self {
+        let Some(prefix) = prefix else {
+            return SelfMetrics::noop();
+        };
+
+        let keys = RefCell::new(HashMap::new());
+        let register = |kind: MetricKind, key: Key| {
+            keys.borrow_mut().insert(key.clone(), kind);
+            key
+        };
+        let counter = |name: &str| {
+            let key = register(
+                MetricKind::Counter,
+                Key::from_name(format!("{prefix}{name}")),
+            );
+            registry.get_or_create_counter(&key, |counter| counter.to_owned().into())
+        };
+        let registry_size = |kind: &'static str| {
+            let key = register(
+                MetricKind::Gauge,
+                Key::from_parts(
+                    format!("{prefix}registry_size"),
+                    vec![Label::new("kind", kind)],
+                ),
+            );
+            registry.get_or_create_gauge(&key, |gauge| gauge.to_owned().into())
+        };
+        let key = register(
+            MetricKind::Histogram,
+            Key::from_name(format!("{prefix}write_duration_seconds")),
+        );
+        let write_duration =
+            registry.get_or_create_histogram(&key, |histogram| histogram.to_owned().into());
+
+        SelfMetrics {
+            flushes: counter("flushes_total"),
+            flush_failures: counter("flush_failures_total"),
+            lines_written: counter("lines_written_total"),
+            bytes_sent: counter("bytes_sent_total"),
+            write_duration,
+            retries: counter("retries_total"),
+            dropped_batches: counter("dropped_batches_total"),
+            dropped_values: counter("dropped_values_total"),
+            rejected_series: counter("rejected_series_total"),
+            counters: registry_size("counter"),
+            gauges: registry_size("gauge"),
+            histograms: registry_size("histogram"),
+            keys: keys.into_inner(),
+        }
+    }
+
+    fn noop() -> Self {
+        SelfMetrics {
+            keys: HashMap::new(),
+            flushes: Counter::noop(),
+            flush_failures: Counter::noop(),
+            lines_written: Counter::noop(),
+            bytes_sent: Counter::noop(),
+            write_duration: Histogram::noop(),
+            retries: Counter::noop(),
+            dropped_batches: Counter::noop(),
+            dropped_values: Counter::noop(),
+            rejected_series: Counter::noop(),
+            counters: Gauge::noop(),
+            gauges: Gauge::noop(),
+            histograms: Gauge::noop(),
+        }
+    }
+
+    /// Whether `key` is one of the exporter's own metrics.
+    pub(crate) fn owns(&self, key: &Key) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    /// Sets the registry size gauges, leaving out the exporter's own metrics.
+    pub(crate) fn set_registry_size(&self, registry: &Registry<Key, GenerationalAtomicStorage>) {
+        let size = |kind: MetricKind, registered: usize| {
+            let own = self.keys.values().filter(|own| **own == kind).count();
+            registered.saturating_sub(own) as f64
+        };
+        self.counters.set(size(
+            MetricKind::Counter,
+            registry.get_counter_handles().len(),
+        ));
+        self.gauges
+            .set(size(MetricKind::Gauge, registry.get_gauge_handles().len()));
+        self.histograms.set(size(
+            MetricKind::Histogram,
+            registry.get_histogram_handles().len(),
+        ));
+    }
+}
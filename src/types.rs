@@ -11,14 +11,14 @@ pub enum Type {
 
 impl Display for Type {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let str = match self {
-            Type::Boolean(value) => value.to_string(),
-            Type::Float(value) => value.to_string(),
-            Type::SignedInteger(value) => format!("{}i", value.to_string()),
-            Type::UnsignedInteger(value) => format!("{}i", value.to_string()),
-            Type::Text(value) => value.clone(),
-        };
-        write!(f, "{str}")
+        match self {
+            Type::Boolean(value) => write!(f, "{value}"),
+            Type::Float(value) => write!(f, "{value}"),
+            Type::SignedInteger(value) => write!(f, "{value}i"),
+            // Without the `u` suffix the value has to fit in a signed integer.
+            Type::UnsignedInteger(value) => write!(f, "{}i", (*value).min(i64::MAX as u64)),
+            Type::Text(value) => f.write_str(value),
+        }
     }
 }
 